@@ -0,0 +1,285 @@
+use std::fmt;
+use std::path::Path;
+use crate::config::{apply_env_overrides, parse_value, Config, ConfigError, Format};
+use crate::notifications::registry::ProviderRegistry;
+use crate::secrets;
+use crate::wg::is_valid_public_key;
+
+/// A problem found in a config file, with the line it was found on if it could be located.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub line : Option<usize>,
+    pub msg : String
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.msg),
+            None => f.write_str(&self.msg)
+        }
+    }
+}
+
+/// Checks the config file at `path` more thoroughly than loading it does: every provider
+/// instance is built with the provider types of `registry`, public keys are checked to be
+/// WireGuard keys, subnets to not have host bits set and routes to refer to existing providers.
+pub fn check_file(path : &Path, registry : &ProviderRegistry) -> Vec<Problem> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) => return vec![Problem { line: None, msg: format!("Unable to read {}: {}", path.display(), err) }]
+    };
+    let format = Format::of(path);
+
+    let mut value = match parse_value(text.as_bytes(), format) {
+        Ok(value) => value,
+        Err(err) => return vec![Problem { line: error_line(&err), msg: err.to_string() }]
+    };
+    let resolved = apply_env_overrides(&mut value, std::env::vars()).and_then(|_| secrets::resolve(&mut value));
+    if let Err(err) = resolved {
+        return vec![Problem { line: None, msg: err.to_string() }];
+    }
+
+    let conf = match serde_yaml::from_value::<Config>(value) {
+        Ok(conf) => conf,
+        Err(err) => {
+            // Values don't know where they came from, so the location is taken from the text
+            // when it fails the same way, or from the field named in the error
+            let line = match format {
+                Format::Yaml => serde_yaml::from_str::<Config>(&text).err().and_then(|err| err.location()).map(|loc| loc.line()),
+                _ => None
+            };
+            let line = line.or_else(|| quoted_name(&err.to_string()).and_then(|name| line_of_key(&text, &name)));
+            return vec![Problem { line, msg: err.to_string() }];
+        }
+    };
+
+    check(&conf, &text, registry)
+}
+
+/// Checks a loaded config, looking up the lines of problems in `text`.
+pub fn check(conf : &Config, text : &str, registry : &ProviderRegistry) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut problem = |sections : &[&str], needle : &str, msg : String| {
+        let line = sections.iter().find_map(|section| line_of(text, section, needle));
+        problems.push(Problem { line, msg });
+    };
+
+    let mut instances : Vec<&String> = conf.notification_providers.keys().collect();
+    instances.sort();
+    for instance in instances {
+        if let Err(err) = registry.build(conf, instance) {
+            problem(&["notification_providers"], instance, error_message(err));
+        }
+    }
+
+    let names = conf.friendly_names.keys().map(|key| ("friendly_names", key))
+        .chain(conf.peer_tags.keys().map(|key| ("peer_tags", key)))
+        .chain(conf.unknown_peers.iter().flat_map(|unknown| unknown.known.iter()).map(|key| ("unknown_peers", key)));
+    for (section, key) in names {
+        if !is_valid_public_key(key) {
+            problem(&[section], key, format!("{} isn't a WireGuard public key, expected 32 bytes encoded as base64", key));
+        }
+    }
+
+    if let Some(usage) = &conf.usage {
+        for peer in usage.quotas.keys() {
+            if !is_valid_public_key(peer) && !conf.friendly_names.values().any(|name| name == peer) {
+                problem(&["usage"], peer, format!("Quota for {} which is neither a public key nor a friendly name", peer));
+            }
+        }
+    }
+
+    let subnets = conf.ignored_subnets.iter().chain(conf.routes.iter().flat_map(|route| route.subnets.iter()));
+    for net in subnets {
+        if net.trunc() != *net {
+            problem(&["ignored_subnets", "routes"], &net.to_string(), format!("Subnet {} has host bits set, did you mean {}?", net, net.trunc()));
+        }
+    }
+
+    let report_providers = conf.reports.iter().flat_map(|report| report.providers.iter());
+    for provider in report_providers {
+        if !conf.notification_providers.contains_key(provider) {
+            problem(&["reports"], provider, format!("Report refers to unknown provider {}", provider));
+        }
+    }
+
+    for route in &conf.routes {
+        for provider in &route.providers {
+            if !conf.notification_providers.contains_key(provider) {
+                problem(&["routes"], provider, format!("Route refers to unknown provider {}", provider));
+            }
+        }
+    }
+
+    if let Err(err) = conf.validate() {
+        problems.push(Problem { line: line_of_key(text, "update_interval"), msg: error_message(err) });
+    }
+
+    problems
+}
+
+fn error_message(err : ConfigError) -> String {
+    match err {
+        ConfigError::Message(msg) => msg,
+        err => err.to_string()
+    }
+}
+
+fn error_line(err : &ConfigError) -> Option<usize> {
+    match err {
+        ConfigError::YamlError(err) => err.location().map(|loc| loc.line()),
+        ConfigError::TomlError(err) => err.line_col().map(|(line, _)| line + 1),
+        ConfigError::JsonError(err) => Some(err.line()),
+        _ => None
+    }
+}
+
+/// Returns the first name quoted with backticks in a serde error, e.g. the unknown field.
+fn quoted_name(msg : &str) -> Option<String> {
+    let start = msg.find('`')? + 1;
+    let len = msg[start..].find('`')?;
+    Some(msg[start..start + len].to_owned())
+}
+
+/// Returns the number of the first line within the top-level `section` that has `needle` as a
+/// key or a value, rather than as part of another name or a URL. Files that aren't laid out
+/// like YAML, e.g. TOML, are searched as a whole.
+fn line_of(text : &str, section : &str, needle : &str) -> Option<usize> {
+    let lines : Vec<&str> = text.lines().collect();
+    let range = match lines.iter().position(|line| key_of(line) == Some(section) && !line.starts_with(char::is_whitespace)) {
+        Some(start) => {
+            let len = lines[start + 1..].iter()
+                .position(|line| !(line.trim().is_empty() || line.starts_with(char::is_whitespace) || line.starts_with('-') || line.starts_with('#')))
+                .unwrap_or(lines.len() - start - 1);
+            start..start + 1 + len
+        },
+        None => 0..lines.len()
+    };
+
+    range.into_iter()
+        .find(|&idx| key_of(lines[idx]) == Some(needle) || has_value(lines[idx], needle))
+        .map(|idx| idx + 1)
+}
+
+/// Returns the number of the first line with `key` as a key, in YAML, TOML or JSON.
+fn line_of_key(text : &str, key : &str) -> Option<usize> {
+    text.lines().position(|line| key_of(line) == Some(key)).map(|idx| idx + 1)
+}
+
+/// Returns the key of a line such as `key: val`, `- "key": val` or `key = val`.
+fn key_of(line : &str) -> Option<&str> {
+    let line = line.trim_start();
+    if line.starts_with('#') || line.starts_with("//") {
+        return None;
+    }
+    let line = line.strip_prefix('-').unwrap_or(line).trim_start();
+
+    let (key, rest) = match line.strip_prefix(['"', '\'']) {
+        Some(quoted) => {
+            let end = quoted.find(['"', '\''])?;
+            (&quoted[..end], quoted[end + 1..].trim_start())
+        },
+        // A colon has to be followed by a space or the end of the line, unlike in `10.0.0.1:80`,
+        // and keys may end in `=` like base64 public keys
+        None => match line.find(": ").or_else(|| line.strip_suffix(':').map(str::len)) {
+            Some(end) => (line[..end].trim_end(), &line[end..]),
+            None => {
+                let end = line.find('=')?;
+                (line[..end].trim_end(), &line[end..])
+            }
+        }
+    };
+
+    let is_key = rest.starts_with('=') || rest.strip_prefix(':').is_some_and(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace));
+    (is_key && !key.is_empty() && !key.contains(char::is_whitespace)).then_some(key)
+}
+
+/// Whether `needle` is one of the values of a line, e.g. `- val` or `[a, val]`.
+fn has_value(line : &str, needle : &str) -> bool {
+    let line = line.trim_start();
+    if line.starts_with('#') || line.starts_with("//") {
+        return false;
+    }
+
+    let is_delimiter = |c : char| c.is_whitespace() || "[]{},\"'-:=".contains(c);
+    line.match_indices(needle).any(|(idx, _)| {
+        let before = line[..idx].chars().next_back();
+        let after = line[idx + needle.len()..].chars().next();
+        before.is_none_or(is_delimiter) && after.is_none_or(|c| c != ':' && is_delimiter(c))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::notifications::registry::ProviderRegistry;
+    use super::{check, check_file};
+
+    #[test]
+    fn test_check_config() {
+        let text = r#"
+notification_providers:
+  discord:
+    webhook_url: https://discord.com/api/webhooks/123
+    enable: true
+  pushover:
+    api_key: abc
+    enable: true
+friendly_names:
+  QXNodG9uIFNoZXJ5bCBNb3JzZQ==: laptop
+  Zo5RsZ9KTs8gRm3I6Au3pS1Ya7Ta8nV7NPpVXR47DHM=: phone
+ignored_subnets:
+  - 10.0.0.1/8
+routes:
+  - providers: [discrod]
+update_interval: 0
+"#;
+        let conf : Config = serde_yaml::from_str(text).unwrap();
+        let problems : Vec<String> = check(&conf, text, &ProviderRegistry::default()).iter().map(|p| p.to_string()).collect();
+
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+        assert!(problems[0].starts_with("line 6: Invalid pushover config for pushover"));
+        assert!(problems[1].starts_with("line 10: QXNodG9uIFNoZXJ5bCBNb3JzZQ== isn't a WireGuard public key"));
+        assert_eq!(problems[2], "line 13: Subnet 10.0.0.1/8 has host bits set, did you mean 10.0.0.0/8?");
+        assert_eq!(problems[3], "line 15: Route refers to unknown provider discrod");
+        assert_eq!(problems[4], "line 16: update_interval must be at least 1 second");
+
+        let err = serde_yaml::from_str::<Config>("notifcation_providers: {}\n").unwrap_err();
+        assert!(err.to_string().contains("unknown field `notifcation_providers`"));
+    }
+
+    #[test]
+    fn test_problem_lines() {
+        // Names appearing earlier as part of a URL or another key don't count
+        let text = r#"
+notification_providers:
+  discord:
+    webhook_url: https://example.com/hooks/pager
+    enable: true
+  pager_backup:
+    type: discord
+    webhook_url: https://discord.com/api/webhooks/123
+routes:
+  - providers: [pager]
+"#;
+        let conf : Config = serde_yaml::from_str(text).unwrap();
+        let problems : Vec<String> = check(&conf, text, &ProviderRegistry::default()).iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems, vec!["line 10: Route refers to unknown provider pager"]);
+
+        let path = std::env::temp_dir().join(format!("wg_activity_notify_check_{}.toml", std::process::id()));
+        std::fs::write(&path, r#"
+[notification_providers.discord]
+webhook_url = "https://example.com/max_backoffs"
+
+[delivery]
+max_attempts = 3
+max_backoffs = 60
+"#).unwrap();
+        let problems = check_file(&path, &ProviderRegistry::default());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].line, Some(7));
+        assert!(problems[0].msg.contains("unknown field `max_backoffs`"), "{}", problems[0].msg);
+    }
+}
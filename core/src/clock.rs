@@ -0,0 +1,54 @@
+use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Duration, Utc};
+
+/// Source of the current time, so detection can be driven by a fake clock in tests.
+pub trait Clock : Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Seconds since the unix epoch, 0 for times before it.
+    fn epoch(&self) -> u64 {
+        self.now().timestamp().max(0) as u64
+    }
+}
+
+/// The system clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct FakeClock {
+    now : Arc<Mutex<DateTime<Utc>>>
+}
+
+impl FakeClock {
+    pub fn new(now : DateTime<Utc>) -> Self {
+        Self { now: Arc::new(Mutex::new(now)) }
+    }
+
+    /// A clock starting at the given unix timestamp.
+    pub fn at_epoch(secs : i64) -> Self {
+        Self::new(DateTime::from_timestamp(secs, 0).expect("Timestamp out of range"))
+    }
+
+    pub fn set(&self, now : DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, secs : i64) {
+        let mut now = self.now.lock().unwrap();
+        *now += Duration::seconds(secs);
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
@@ -1,11 +1,26 @@
 use std::collections::HashMap;
-use std::io::{Error, Read};
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use serde::{Deserialize, Serialize};
 use serde::de::DeserializeOwned;
 use thiserror::Error;
+use tracing::warn;
 use ipnet::IpNet;
+use crate::history::HistoryConfig;
+use crate::http::HttpConfig;
+use crate::notifications::{Event, Severity};
+use crate::notifications::delivery::DeliveryConfig;
+use crate::report::ReportConfig;
+use crate::routing::Route;
+use crate::schedule::Schedule;
+use crate::secrets;
+use crate::stability::{DebounceConfig, FlapConfig};
+use crate::stale::StaleConfig;
+use crate::unknown::UnknownPeersConfig;
+use crate::usage::UsageConfig;
 
-#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     #[serde(default = "default_providers")]
     pub notification_providers : std::collections::HashMap<String, serde_yaml::Value>,
@@ -13,31 +28,296 @@ pub struct Config {
     pub friendly_names : std::collections::HashMap<String, String>,
     #[serde(default = "default_ignored_subnets")]
     pub ignored_subnets : Vec<IpNet>,
+    /// Tags per public key, which routes can match on
+    #[serde(default = "default_peer_tags")]
+    pub peer_tags : std::collections::HashMap<String, Vec<String>>,
+    #[serde(default = "default_routes")]
+    pub routes : Vec<Route>,
+    #[serde(default)]
+    pub delivery : DeliveryConfig,
+    /// Default severity per event type, providers can override it with their own `severity`
+    #[serde(default)]
+    pub severity : HashMap<Event, Severity>,
+    #[serde(default)]
+    pub debounce : DebounceConfig,
+    #[serde(default)]
+    pub flap_detection : FlapConfig,
+    /// Send a notification when the daemon is stopped
+    #[serde(default)]
+    pub notify_on_shutdown : bool,
+    /// Send notifications when a connected peer changes endpoint
+    #[serde(default)]
+    pub notify_roaming : bool,
+    /// Schedule of the digest summarising sessions per peer
+    #[serde(default)]
+    pub digest : Option<Schedule>,
+    /// Notify about peers that never connected or haven't in a while, disabled if left out
+    #[serde(default)]
+    pub stale_peers : Option<StaleConfig>,
+    /// Alert when a peer that isn't in the inventory connects, disabled if left out
+    #[serde(default)]
+    pub unknown_peers : Option<UnknownPeersConfig>,
+    /// Status reports listing every peer
+    #[serde(default)]
+    pub reports : Vec<ReportConfig>,
     #[serde(default = "default_update_interval")]
     pub update_interval : u64,
+    /// Traffic accounting and quotas per peer, disabled if left out
+    #[serde(default)]
+    pub usage : Option<UsageConfig>,
+    /// Database recording the sessions of the peers, disabled if left out
+    #[serde(default)]
+    pub history : Option<HistoryConfig>,
+    /// HTTP listener serving metrics, disabled if left out
+    #[serde(default)]
+    pub http : Option<HttpConfig>,
+    /// Reload the config when the file changes, in addition to on SIGHUP
+    #[serde(default)]
+    pub watch_config : bool,
     #[serde(default = "default_log_level")]
     pub log_level : String
 }
 
+impl Default for Config {
+    /// The config of an empty file.
+    fn default() -> Self {
+        serde_yaml::from_value(serde_yaml::Value::Mapping(serde_yaml::Mapping::new()))
+            .expect("Every field has a default")
+    }
+}
+
 impl Config {
+    /// Loads the config from the path in `WG_ACTIVITY_NOTIFY_CONFIG`, or `config.yml`.
     pub fn load() -> Result<Self, ConfigError> {
-        let mut input_file = std::fs::File::open("config.yml")?;
+        Self::load_from(Self::default_path())
+    }
+
+    pub fn default_path() -> PathBuf {
+        std::env::var_os(CONFIG_PATH_VAR).map(PathBuf::from).unwrap_or_else(|| PathBuf::from("config.yml"))
+    }
+
+    /// Loads the config from `path`, as TOML or JSON depending on its extension and as YAML
+    /// otherwise, then applies the overrides from `WG_ACTIVITY_NOTIFY_*` environment variables.
+    pub fn load_from<P : AsRef<Path>>(path : P) -> Result<Self, ConfigError> {
+        Self::load_with_env(path, std::env::vars())
+    }
+
+    /// Like `load_from`, taking the environment variables from `vars`.
+    pub fn load_with_env<P : AsRef<Path>, I : IntoIterator<Item = (String, String)>>(path : P, vars : I) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let mut input_file = std::fs::File::open(path)?;
         let mut buf = Vec::new();
         input_file.read_to_end(&mut buf)?;
-        Ok(serde_yaml::from_slice::<Config>(&buf)?)
+
+        let mut value = parse_value(&buf, Format::of(path))?;
+        apply_env_overrides(&mut value, vars)?;
+        secrets::resolve(&mut value)?;
+        let conf = serde_yaml::from_value::<Config>(value)?;
+        conf.validate()?;
+        Ok(conf)
     }
 
-    pub fn get_notification_provider_config<T : DeserializeOwned>(provider_name : &str) -> Result<T, ConfigError> {
-        let root_conf = Config::load().expect("Couldn't find config file");
-        let config_raw = root_conf.notification_providers.get(provider_name).expect(format!("No {} config entry was found", provider_name).as_str());
+    /// Checks what deserialising can't, e.g. that `update_interval` isn't zero.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.update_interval == 0 {
+            return Err(ConfigError::Message("update_interval must be at least 1 second".to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Deserialises the config of the provider instance named `instance_name`.
+    pub fn get_notification_provider_config<T : DeserializeOwned>(&self, instance_name : &str) -> Result<T, ConfigError> {
+        let config_raw = self.notification_providers.get(instance_name)
+            .ok_or_else(|| ConfigError::Message(format!("No {} config entry was found", instance_name)))?;
 
         match serde_yaml::from_value::<T>(config_raw.clone()) {
             Ok(val) => Ok(val),
             Err(err) => Err(err.into())
         }
     }
+
+    /// Returns the provider type of an instance. Instances without an explicit `type` field
+    /// fall back to their name, so `discord:` and `pushover:` sections keep working as before.
+    pub fn get_notification_provider_type(&self, instance_name : &str) -> Option<String> {
+        let config_raw = self.notification_providers.get(instance_name)?;
+        match config_raw.get("type").and_then(|val| val.as_str()) {
+            Some(kind) => Some(kind.to_lowercase()),
+            None => Some(instance_name.to_lowercase())
+        }
+    }
 }
 
+// Written by hand so secrets don't end up in logs
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f : &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn redacted<T : Serialize>(val : &T) -> serde_yaml::Value {
+            serde_yaml::to_value(val).map(|val| secrets::redact(&val)).unwrap_or(serde_yaml::Value::Null)
+        }
+
+        f.debug_struct("Config")
+            .field("notification_providers", &redacted(&self.notification_providers))
+            .field("friendly_names", &redacted(&self.friendly_names))
+            .field("ignored_subnets", &self.ignored_subnets)
+            .field("peer_tags", &redacted(&self.peer_tags))
+            .field("routes", &redacted(&self.routes))
+            .field("delivery", &redacted(&self.delivery))
+            .field("severity", &self.severity)
+            .field("debounce", &self.debounce)
+            .field("flap_detection", &self.flap_detection)
+            .field("notify_on_shutdown", &self.notify_on_shutdown)
+            .field("notify_roaming", &self.notify_roaming)
+            .field("digest", &redacted(&self.digest))
+            .field("update_interval", &self.update_interval)
+            .field("stale_peers", &self.stale_peers)
+            .field("unknown_peers", &redacted(&self.unknown_peers))
+            .field("reports", &redacted(&self.reports))
+            .field("usage", &redacted(&self.usage))
+            .field("history", &redacted(&self.history))
+            .field("http", &redacted(&self.http))
+            .field("watch_config", &self.watch_config)
+            .field("log_level", &self.log_level)
+            .finish()
+    }
+}
+
+/// Environment variable holding the path of the config file.
+pub const CONFIG_PATH_VAR : &str = "WG_ACTIVITY_NOTIFY_CONFIG";
+
+/// Prefix of the environment variables overriding config fields.
+pub const ENV_PREFIX : &str = "WG_ACTIVITY_NOTIFY_";
+
+/// Suffix of the environment variables whose value is always parsed as YAML.
+pub const YAML_SUFFIX : &str = "__YAML";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json
+}
+
+impl Format {
+    pub fn of(path : &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase()).as_deref() {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Yaml
+        }
+    }
+}
+
+/// Parses a config file into a YAML value, whatever its format.
+pub fn parse_value(buf : &[u8], format : Format) -> Result<serde_yaml::Value, ConfigError> {
+    let value = match format {
+        Format::Yaml => serde_yaml::from_slice(buf)?,
+        Format::Toml => serde_yaml::to_value(toml::from_slice::<toml::Value>(buf)?)?,
+        Format::Json => serde_yaml::to_value(serde_json::from_slice::<serde_json::Value>(buf)?)?
+    };
+
+    // An empty YAML file is null rather than an empty mapping
+    Ok(match value {
+        serde_yaml::Value::Null => serde_yaml::Value::Mapping(serde_yaml::Mapping::new()),
+        value => value
+    })
+}
+
+/// Applies every `WG_ACTIVITY_NOTIFY_*` variable of `vars` to the config.
+///
+/// Nested fields are separated by a double underscore, e.g.
+/// `WG_ACTIVITY_NOTIFY_NOTIFICATION_PROVIDERS__DISCORD__WEBHOOK_URL`. Names are matched against
+/// existing keys case-insensitively, new keys are lowercased.
+///
+/// Values are taken as strings, so secrets such as `123456` or `abc #x` are kept as they are.
+/// They're parsed as YAML when the field they replace, in the file or by default, isn't a
+/// string, so `true`, `10` or `[a, b]` work as expected, or when the name ends in `__YAML`.
+/// Numbers and booleans for a section missing from the file, e.g. `HTTP__PORT=9586`, are
+/// parsed as well, unless they're for a provider instance.
+///
+/// Variables that don't start with a top-level field, e.g. the `*_SERVICE_HOST` variables
+/// Kubernetes sets for a service, are skipped with a warning.
+pub fn apply_env_overrides<I : IntoIterator<Item = (String, String)>>(value : &mut serde_yaml::Value, vars : I) -> Result<(), ConfigError> {
+    let defaults = serde_yaml::to_value(Config::default())?;
+
+    for (name, raw) in vars {
+        if name == CONFIG_PATH_VAR {
+            continue;
+        }
+        let path = match name.strip_prefix(ENV_PREFIX) {
+            Some(path) if !path.is_empty() => path,
+            _ => continue
+        };
+        let (path, as_yaml) = match path.strip_suffix(YAML_SUFFIX) {
+            Some(path) => (path, true),
+            None => (path, false)
+        };
+        let keys : Vec<&str> = path.split("__").collect();
+        if lookup(&defaults, &keys[..1]).is_none() {
+            warn!("Ignoring environment variable {}, {} isn't a config field", name, keys[0].to_lowercase());
+            continue;
+        }
+
+        let replaced = lookup(value, &keys).or_else(|| lookup(&defaults, &keys));
+        let parsed = match serde_yaml::from_str::<serde_yaml::Value>(&raw) {
+            Ok(parsed) if as_yaml && !parsed.is_null() => parsed,
+            Ok(parsed) => match replaced {
+                Some(val) if !val.is_string() && !val.is_null() && !parsed.is_null() => parsed,
+                Some(_) => serde_yaml::Value::String(raw.clone()),
+                // A field of a section left out of the file, whose type isn't known. Numbers and
+                // booleans are taken as such, except in provider instances where they're likely secrets
+                None if !is_provider_field(&keys) && matches!(parsed, serde_yaml::Value::Number(_) | serde_yaml::Value::Bool(_)) => parsed,
+                None => serde_yaml::Value::String(raw.clone())
+            },
+            Err(_) => serde_yaml::Value::String(raw.clone())
+        };
+        set_path(value, &keys, parsed)
+            .map_err(|msg| ConfigError::Message(format!("Unable to apply {}: {}", name, msg)))?;
+    }
+
+    Ok(())
+}
+
+fn is_provider_field(keys : &[&str]) -> bool {
+    keys.len() > 2 && keys[0].eq_ignore_ascii_case("notification_providers")
+}
+
+/// Returns the value at `keys`, matching them case-insensitively.
+fn lookup<'a>(value : &'a serde_yaml::Value, keys : &[&str]) -> Option<&'a serde_yaml::Value> {
+    keys.iter().try_fold(value, |value, key| {
+        value.as_mapping()?.iter()
+            .find(|(name, _)| name.as_str().is_some_and(|name| name.eq_ignore_ascii_case(key)))
+            .map(|(_, val)| val)
+    })
+}
+
+fn set_path(value : &mut serde_yaml::Value, keys : &[&str], new : serde_yaml::Value) -> Result<(), String> {
+    let (first, rest) = match keys.split_first() {
+        Some(split) => split,
+        None => {
+            *value = new;
+            return Ok(());
+        }
+    };
+
+    if value.is_null() {
+        *value = serde_yaml::Value::Mapping(serde_yaml::Mapping::new());
+    }
+    let mapping = match value {
+        serde_yaml::Value::Mapping(mapping) => mapping,
+        _ => return Err(format!("{} isn't a section", first.to_lowercase()))
+    };
+
+    let key = mapping.iter()
+        .filter_map(|(key, _)| key.as_str())
+        .find(|key| key.eq_ignore_ascii_case(first))
+        .map(|key| key.to_owned())
+        .unwrap_or_else(|| first.to_lowercase());
+    let key = serde_yaml::Value::String(key);
+
+    if !mapping.contains_key(&key) {
+        mapping.insert(key.clone(), serde_yaml::Value::Null);
+    }
+    set_path(mapping.get_mut(&key).expect("Key was just inserted"), rest, new)
+}
 
 fn default_providers() -> HashMap<String, serde_yaml::Value> {
     HashMap::new()
@@ -53,6 +333,12 @@ fn default_log_level() -> String {
 
 fn default_ignored_subnets() -> Vec<IpNet> {Vec::new()}
 
+fn default_peer_tags() -> HashMap<String, Vec<String>> {
+    HashMap::new()
+}
+
+fn default_routes() -> Vec<Route> {Vec::new()}
+
 
 fn default_update_interval() -> u64 { 5 }
 
@@ -61,13 +347,17 @@ pub enum ConfigError {
     #[error("unknown config error")]
     Unknown,
     #[error("config error: {0:?}")]
-    CustomError(Box<dyn std::error::Error>),
+    CustomError(Box<dyn std::error::Error + Send + Sync>),
     #[error("config error: {0}")]
     Message(String),
     #[error("io error: {0:?}")]
     IoError(std::io::Error),
     #[error("yaml error: {0:?}")]
-    YamlError(serde_yaml::Error)
+    YamlError(serde_yaml::Error),
+    #[error("toml error: {0:?}")]
+    TomlError(toml::de::Error),
+    #[error("json error: {0:?}")]
+    JsonError(serde_json::Error)
 }
 
 impl From<std::io::Error> for ConfigError {
@@ -80,4 +370,90 @@ impl From<serde_yaml::Error> for ConfigError {
     fn from(e: serde_yaml::Error) -> Self {
         Self::YamlError(e)
     }
-}
\ No newline at end of file
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> Self {
+        Self::TomlError(e)
+    }
+}
+
+impl From<serde_json::Error> for ConfigError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::JsonError(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_env_overrides, parse_value, Config, Format};
+
+    #[test]
+    fn test_formats_and_env_overrides() {
+        let toml = r#"
+update_interval = 10
+
+[notification_providers.discord]
+webhook_url = "https://discord.com/api/webhooks/old"
+"#;
+        let mut value = parse_value(toml.as_bytes(), Format::Toml).unwrap();
+        let json = parse_value(br#"{"update_interval": 10}"#, Format::Json).unwrap();
+        assert_eq!(value["update_interval"], json["update_interval"]);
+
+        apply_env_overrides(&mut value, vec![
+            ("WG_ACTIVITY_NOTIFY_NOTIFICATION_PROVIDERS__DISCORD__WEBHOOK_URL".to_owned(), "https://discord.com/api/webhooks/new".to_owned()),
+            ("WG_ACTIVITY_NOTIFY_NOTIFY_ROAMING".to_owned(), "true".to_owned()),
+            ("WG_ACTIVITY_NOTIFY_IGNORED_SUBNETS".to_owned(), "[10.0.0.0/8]".to_owned()),
+            ("WG_ACTIVITY_NOTIFY_NOTIFICATION_PROVIDERS__PUSHOVER__API_KEY".to_owned(), "123456".to_owned()),
+            ("WG_ACTIVITY_NOTIFY_NOTIFICATION_PROVIDERS__PUSHOVER__DEVICE_KEY".to_owned(), "abc #x".to_owned()),
+            ("WG_ACTIVITY_NOTIFY_NOTIFICATION_PROVIDERS__PUSHOVER__PRIORITY__YAML".to_owned(), "1".to_owned()),
+            ("WG_ACTIVITY_NOTIFY_LOG_LEVEL".to_owned(), "a: b".to_owned()),
+            ("WG_ACTIVITY_NOTIFY_HISTORY__PATH".to_owned(), "sessions.db".to_owned()),
+            ("WG_ACTIVITY_NOTIFY_HISTORY__RETENTION".to_owned(), "30".to_owned()),
+            ("WG_ACTIVITY_NOTIFY_CONFIG".to_owned(), "config.toml".to_owned()),
+            ("HOME".to_owned(), "/root".to_owned())
+        ]).unwrap();
+
+        let conf : Config = serde_yaml::from_value(value).unwrap();
+        assert_eq!(conf.update_interval, 10);
+        assert!(conf.notify_roaming);
+        assert_eq!(conf.ignored_subnets.len(), 1);
+        assert_eq!(conf.notification_providers["discord"]["webhook_url"].as_str(), Some("https://discord.com/api/webhooks/new"));
+        let pushover = &conf.notification_providers["pushover"];
+        assert_eq!(pushover["api_key"].as_str(), Some("123456"));
+        assert_eq!(pushover["device_key"].as_str(), Some("abc #x"));
+        assert_eq!(pushover["priority"].as_i64(), Some(1));
+        assert_eq!(conf.log_level, "a: b");
+        assert!(!format!("{:?}", conf).contains("webhooks/new"));
+        // Into a section that isn't in the file
+        let history = conf.history.unwrap();
+        assert_eq!((history.path.to_str(), history.retention), (Some("sessions.db"), 30));
+    }
+
+    #[test]
+    fn test_unrelated_env_vars() {
+        let path = std::env::temp_dir().join(format!("wg_activity_notify_env_{}.yml", std::process::id()));
+        std::fs::write(&path, "update_interval: 10\n").unwrap();
+
+        // As set by Kubernetes for a service named wg-activity-notify
+        let conf = Config::load_with_env(&path, vec![
+            ("WG_ACTIVITY_NOTIFY_SERVICE_HOST".to_owned(), "10.96.0.12".to_owned()),
+            ("WG_ACTIVITY_NOTIFY_PORT".to_owned(), "tcp://10.96.0.12:9586".to_owned())
+        ]);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(conf.unwrap().update_interval, 10);
+    }
+
+    #[test]
+    fn test_update_interval() {
+        assert_eq!(Config::default().update_interval, 5);
+        assert!(Config::default().validate().is_ok());
+
+        let path = std::env::temp_dir().join(format!("wg_activity_notify_interval_{}.yml", std::process::id()));
+        std::fs::write(&path, "update_interval: 0\n").unwrap();
+        let err = Config::load_with_env(&path, Vec::new()).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.to_string().contains("update_interval must be at least 1 second"));
+    }
+}
@@ -8,7 +8,7 @@ pub enum Error {
     #[error("unable to get status of entry")]
     UnableToGetStatusOfEntry(),
     #[error("config error: {0:?}")]
-    CustomError(Box<dyn std::error::Error>),
+    CustomError(Box<dyn std::error::Error + Send + Sync>),
     #[error("error: {0}")]
     Message(String),
     #[error("config error: {0:?}")]
@@ -0,0 +1,27 @@
+use serde::{Serialize, Deserialize};
+use crate::notifications::{Event, PeerInfo};
+
+/// An event detected by the daemon, as handed to subscribers.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DaemonEvent {
+    pub event : Event,
+    pub peer : Option<PeerInfo>,
+    /// Friendly name of the peer, if one is configured
+    pub friendly_name : Option<String>,
+    pub msg : String,
+    /// Unix timestamp of when the event was detected
+    pub timestamp : u64
+}
+
+/// Receives the events of a daemon in-process, without going through a notification provider.
+///
+/// `on_event` is called from the poll loop, so it should return quickly.
+pub trait EventSubscriber : Send + Sync {
+    fn on_event(&self, event : &DaemonEvent);
+}
+
+impl<F : Fn(&DaemonEvent) + Send + Sync> EventSubscriber for F {
+    fn on_event(&self, event : &DaemonEvent) {
+        self(event)
+    }
+}
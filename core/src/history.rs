@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use chrono::DateTime;
+use rusqlite::{params, Connection};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use tracing::{debug, error};
+use crate::wg::ClientData;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// SQLite database the sessions are recorded in
+    pub path : PathBuf,
+    /// Days sessions are kept for, 0 keeps them forever
+    #[serde(default = "default_retention")]
+    pub retention : u64
+}
+
+fn default_retention() -> u64 { 90 }
+
+/// A connect→disconnect session of a peer.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Session {
+    pub public_key : String,
+    /// Not recorded, filled in from the config when exporting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub friendly_name : Option<String>,
+    pub interface : String,
+    /// Endpoints used during the session, in the order they were first seen
+    pub endpoints : Vec<String>,
+    /// Unix timestamps
+    pub started : u64,
+    pub ended : u64,
+    /// Seconds
+    pub duration : u64,
+    pub rx_bytes : u64,
+    pub tx_bytes : u64
+}
+
+/// Sessions overlapping a time range, of some peers or all of them.
+#[derive(Clone, Debug, Default)]
+pub struct SessionQuery {
+    /// Public keys, every peer if empty
+    pub peers : Vec<String>,
+    pub from : Option<u64>,
+    pub to : Option<u64>
+}
+
+#[derive(Error, Debug)]
+pub enum HistoryError {
+    #[error("sqlite error: {0}")]
+    Sqlite(rusqlite::Error),
+    #[error("history error: {0}")]
+    Message(String)
+}
+
+impl From<rusqlite::Error> for HistoryError {
+    fn from(e : rusqlite::Error) -> Self {
+        Self::Sqlite(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, HistoryError>;
+
+/// The session history database.
+pub struct HistoryDb {
+    conn : Connection
+}
+
+impl HistoryDb {
+    pub fn open<P : AsRef<Path>>(path : P) -> Result<Self> {
+        Self::init(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn : Connection) -> Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                id INTEGER PRIMARY KEY,
+                public_key TEXT NOT NULL,
+                interface TEXT NOT NULL,
+                endpoints TEXT NOT NULL,
+                started INTEGER NOT NULL,
+                ended INTEGER NOT NULL,
+                rx_bytes INTEGER NOT NULL,
+                tx_bytes INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS sessions_peer ON sessions (public_key, started);
+            CREATE INDEX IF NOT EXISTS sessions_ended ON sessions (ended);"
+        )?;
+        Ok(Self { conn })
+    }
+
+    pub fn insert(&self, session : &Session) -> Result<()> {
+        let endpoints = serde_json::to_string(&session.endpoints)
+            .map_err(|err| HistoryError::Message(err.to_string()))?;
+        self.conn.execute(
+            "INSERT INTO sessions (public_key, interface, endpoints, started, ended, rx_bytes, tx_bytes) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![session.public_key, session.interface, endpoints, session.started as i64, session.ended as i64, session.rx_bytes as i64, session.tx_bytes as i64]
+        )?;
+        Ok(())
+    }
+
+    /// Returns the matching sessions, oldest first.
+    pub fn query(&self, query : &SessionQuery) -> Result<Vec<Session>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT public_key, interface, endpoints, started, ended, rx_bytes, tx_bytes FROM sessions
+             WHERE ended >= ?1 AND started <= ?2 ORDER BY started, id"
+        )?;
+        let from = query.from.unwrap_or(0) as i64;
+        let to = query.to.map(|to| to as i64).unwrap_or(i64::MAX);
+
+        let rows = stmt.query_map(params![from, to], |row| {
+            let endpoints : String = row.get(2)?;
+            let started = row.get::<_, i64>(3)? as u64;
+            let ended = row.get::<_, i64>(4)? as u64;
+            Ok(Session {
+                public_key: row.get(0)?,
+                friendly_name: None,
+                interface: row.get(1)?,
+                endpoints: serde_json::from_str(&endpoints).unwrap_or_default(),
+                started,
+                ended,
+                duration: ended.saturating_sub(started),
+                rx_bytes: row.get::<_, i64>(5)? as u64,
+                tx_bytes: row.get::<_, i64>(6)? as u64
+            })
+        })?;
+
+        let mut sessions = Vec::new();
+        for session in rows {
+            let session = session?;
+            if query.peers.is_empty() || query.peers.contains(&session.public_key) {
+                sessions.push(session);
+            }
+        }
+        Ok(sessions)
+    }
+
+    /// Deletes the sessions that ended before `before`, returning how many were deleted.
+    pub fn prune(&self, before : u64) -> Result<usize> {
+        Ok(self.conn.execute("DELETE FROM sessions WHERE ended < ?1", params![before as i64])?)
+    }
+}
+
+struct OpenSession {
+    interface : String,
+    endpoints : Vec<String>,
+    started : u64,
+    rx_bytes : u64,
+    tx_bytes : u64,
+    last_rx : u64,
+    last_tx : u64
+}
+
+/// Records the sessions of the peers into a `HistoryDb` as they end.
+///
+/// The traffic of a session is summed from the changes of the `wg` counters, so it survives
+/// the counters being reset when an interface is recreated.
+pub struct SessionRecorder {
+    db : HistoryDb,
+    retention : u64,
+    open : HashMap<String, OpenSession>,
+    last_prune : u64
+}
+
+impl SessionRecorder {
+    pub fn new(db : HistoryDb, retention : u64) -> Self {
+        Self {
+            db,
+            retention,
+            open: HashMap::new(),
+            last_prune: 0
+        }
+    }
+
+    pub fn open(conf : &HistoryConfig) -> Result<Self> {
+        Ok(Self::new(HistoryDb::open(&conf.path)?, conf.retention))
+    }
+
+    pub fn db(&self) -> &HistoryDb {
+        &self.db
+    }
+
+    pub fn connected(&mut self, data : &ClientData, now : u64) {
+        if self.open.contains_key(&data.public_key) {
+            return;
+        }
+        self.open.insert(data.public_key.clone(), OpenSession {
+            interface: data.interface.clone(),
+            endpoints: data.endpoint.iter().cloned().collect(),
+            started: now,
+            rx_bytes: 0,
+            tx_bytes: 0,
+            last_rx: data.transfer_rx.max(0) as u64,
+            last_tx: data.transfer_tx.max(0) as u64
+        });
+    }
+
+    /// Adds the traffic and endpoint of a peer to its ongoing session, if it has one.
+    pub fn observe(&mut self, data : &ClientData) {
+        let session = match self.open.get_mut(&data.public_key) {
+            Some(session) => session,
+            None => return
+        };
+
+        let (rx, tx) = (data.transfer_rx.max(0) as u64, data.transfer_tx.max(0) as u64);
+        // A counter going backwards has been reset, everything it counts is new
+        session.rx_bytes += if rx >= session.last_rx { rx - session.last_rx } else { rx };
+        session.tx_bytes += if tx >= session.last_tx { tx - session.last_tx } else { tx };
+        session.last_rx = rx;
+        session.last_tx = tx;
+
+        if let Some(endpoint) = &data.endpoint {
+            if !session.endpoints.contains(endpoint) {
+                session.endpoints.push(endpoint.clone());
+            }
+        }
+    }
+
+    /// Ends the session of a peer at its latest handshake, as it's only noticed to be
+    /// disconnected some time after.
+    pub fn disconnected(&mut self, data : &ClientData, now : u64) {
+        if let Some(session) = self.open.remove(&data.public_key) {
+            let ended = data.latest_handshake.clamp(session.started, now.max(session.started));
+            self.record(&data.public_key, session, ended);
+        }
+    }
+
+    /// Ends every ongoing session, e.g. when the daemon stops.
+    pub fn close_all(&mut self, now : u64) {
+        for (pub_key, session) in std::mem::take(&mut self.open) {
+            self.record(&pub_key, session, now);
+        }
+    }
+
+    /// Returns the sessions overlapping the period from `from` to `now`, including the
+    /// ongoing ones as if they ended `now`.
+    pub fn sessions_since(&self, from : u64, now : u64) -> Result<Vec<Session>> {
+        let mut sessions = self.db.query(&SessionQuery { peers: Vec::new(), from: Some(from), to: Some(now) })?;
+        for (pub_key, session) in &self.open {
+            sessions.push(Session {
+                public_key: pub_key.clone(),
+                friendly_name: None,
+                interface: session.interface.clone(),
+                endpoints: session.endpoints.clone(),
+                started: session.started,
+                ended: now,
+                duration: now.saturating_sub(session.started),
+                rx_bytes: session.rx_bytes,
+                tx_bytes: session.tx_bytes
+            });
+        }
+        Ok(sessions)
+    }
+
+    /// Deletes sessions older than the retention period, at most once a day.
+    pub fn prune(&mut self, now : u64) {
+        if self.retention == 0 || now.saturating_sub(self.last_prune) < 86400 {
+            return;
+        }
+        self.last_prune = now;
+
+        match self.db.prune(now.saturating_sub(self.retention.saturating_mul(86400))) {
+            Ok(0) => {},
+            Ok(deleted) => debug!("Deleted {} sessions past the retention period", deleted),
+            Err(err) => error!("Unable to delete old sessions: {}", err)
+        }
+    }
+
+    fn record(&self, pub_key : &str, session : OpenSession, ended : u64) {
+        let session = Session {
+            public_key: pub_key.to_owned(),
+            friendly_name: None,
+            interface: session.interface,
+            endpoints: session.endpoints,
+            started: session.started,
+            ended,
+            duration: ended.saturating_sub(session.started),
+            rx_bytes: session.rx_bytes,
+            tx_bytes: session.tx_bytes
+        };
+        if let Err(err) = self.db.insert(&session) {
+            error!("Unable to record session of {}: {}", pub_key, err);
+        }
+    }
+}
+
+/// Parses a point in time given as a unix timestamp, an RFC 3339 date and time or a
+/// `YYYY-MM-DD` date (midnight UTC).
+pub fn parse_time(raw : &str) -> Option<u64> {
+    if let Ok(ts) = raw.parse::<u64>() {
+        return Some(ts);
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(raw) {
+        return u64::try_from(time.timestamp()).ok();
+    }
+    let date = chrono::NaiveDate::parse_from_str(raw, "%Y-%m-%d").ok()?;
+    u64::try_from(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp()).ok()
+}
+
+/// Formats sessions as CSV, with timestamps in RFC 3339.
+pub fn to_csv(sessions : &[Session]) -> String {
+    let mut out = String::from("public_key,friendly_name,interface,endpoints,started,ended,duration,rx_bytes,tx_bytes\n");
+    let time = |ts : u64| DateTime::from_timestamp(ts as i64, 0).map(|t| t.to_rfc3339()).unwrap_or_default();
+
+    for session in sessions {
+        let fields = [
+            session.public_key.clone(),
+            session.friendly_name.clone().unwrap_or_default(),
+            session.interface.clone(),
+            session.endpoints.join(" "),
+            time(session.started),
+            time(session.ended),
+            session.duration.to_string(),
+            session.rx_bytes.to_string(),
+            session.tx_bytes.to_string()
+        ];
+        let fields : Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        out.push_str(&fields.join(","));
+        out.push('\n');
+    }
+
+    out
+}
+
+fn csv_field(field : &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wg::{parse_dump, WgEntry};
+    use super::{parse_time, to_csv, HistoryDb, SessionQuery, SessionRecorder};
+
+    fn client(endpoint : &str, handshake : u64, rx : u64, tx : u64) -> crate::wg::ClientData {
+        let line = format!("wg0\tZo5RsZ9KTs8gRm3I6Au3pS1Ya7Ta8nV7NPpVXR47DHM=\t(none)\t{}\t10.2.98.3/32\t{}\t{}\t{}\t25\n", endpoint, handshake, rx, tx);
+        match parse_dump(line).remove(0) {
+            WgEntry::Client(data) => data,
+            WgEntry::Server(_) => unreachable!()
+        }
+    }
+
+    #[test]
+    fn test_recorder() {
+        let mut recorder = SessionRecorder::new(HistoryDb::open_in_memory().unwrap(), 30);
+        recorder.connected(&client("10.2.2.68:62299", 1000, 1000, 500), 1000);
+        recorder.observe(&client("10.2.2.68:62299", 1300, 3000, 700));
+        // The interface was recreated, resetting the counters
+        recorder.observe(&client("192.0.2.10:51820", 1600, 100, 50));
+        // Noticed well after the last handshake, which is when the session really ended
+        recorder.disconnected(&client("192.0.2.10:51820", 1600, 100, 50), 1900);
+
+        let sessions = recorder.db().query(&SessionQuery::default()).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].endpoints, vec!["10.2.2.68:62299", "192.0.2.10:51820"]);
+        assert_eq!((sessions[0].rx_bytes, sessions[0].tx_bytes), (2100, 250));
+        assert_eq!(sessions[0].duration, 600);
+
+        let query = SessionQuery { peers: Vec::new(), from: Some(1700), to: None };
+        assert!(recorder.db().query(&query).unwrap().is_empty());
+
+        let csv = to_csv(&sessions);
+        assert!(csv.lines().nth(1).unwrap().starts_with("Zo5RsZ9KTs8gRm3I6Au3pS1Ya7Ta8nV7NPpVXR47DHM=,,wg0,10.2.2.68:62299 192.0.2.10:51820,1970-01-01T00:16:40+00:00"));
+
+        assert_eq!(parse_time("2024-03-01"), Some(1709251200));
+        assert_eq!(parse_time("2024-03-01T01:00:00+01:00"), Some(1709251200));
+        assert_eq!(parse_time("yesterday"), None);
+
+        // A handshake in the future doesn't make the session end after it's noticed
+        recorder.connected(&client("10.2.2.68:62299", 2000, 0, 0), 2000);
+        recorder.disconnected(&client("10.2.2.68:62299", 9000, 0, 0), 2500);
+        let query = SessionQuery { peers: Vec::new(), from: Some(2000), to: None };
+        assert_eq!(recorder.db().query(&query).unwrap()[0].ended, 2500);
+
+        recorder.prune(2500 + 31 * 86400);
+        assert!(recorder.db().query(&SessionQuery::default()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,227 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use axum::{Json, Router};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::response::sse::{self, KeepAlive, Sse};
+use axum::routing::get;
+use serde::{Serialize, Deserialize};
+use futures_util::stream::{self, Stream, StreamExt};
+use serde_json::json;
+use tokio::sync::broadcast;
+use tracing::{error, info, warn};
+use crate::clock::Clock;
+use crate::events::DaemonEvent;
+use crate::metrics::Metrics;
+use crate::view::SharedView;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Address to listen on, e.g. `127.0.0.1:9586`
+    pub listen : SocketAddr,
+    /// Number of recent events kept for `/events`
+    #[serde(default = "default_event_buffer")]
+    pub event_buffer : usize
+}
+
+pub fn default_event_buffer() -> usize { 1000 }
+
+/// What the HTTP listener serves, shared with the daemon.
+#[derive(Clone)]
+pub struct HttpState {
+    pub metrics : Metrics,
+    pub view : SharedView,
+    /// Events as they are detected, sent while holding the write lock of `view`
+    pub events : broadcast::Sender<DaemonEvent>,
+    pub clock : Arc<dyn Clock>
+}
+
+pub fn router(state : HttpState) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .route("/health", get(health))
+        .route("/peers", get(peers))
+        .route("/peers/{key}", get(peer))
+        .route("/events", get(events))
+        .route("/events/stream", get(stream))
+        .with_state(state)
+}
+
+/// Serves `router` on `listen` until the task is aborted.
+pub async fn serve(listen : SocketAddr, state : HttpState) {
+    let listener = match tokio::net::TcpListener::bind(listen).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Unable to listen on {}: {}", listen, err);
+            return;
+        }
+    };
+
+    info!("Listening on http://{}", listen);
+    if let Err(err) = axum::serve(listener, router(state)).await {
+        error!("HTTP listener stopped: {}", err);
+    }
+}
+
+async fn metrics(State(state) : State<HttpState>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], state.metrics.encode())
+}
+
+/// Healthy once a poll has succeeded, as long as the latest one succeeded and isn't overdue.
+async fn health(State(state) : State<HttpState>) -> Response {
+    let health = state.view.read().unwrap().health.clone();
+    let overdue = health.last_poll
+        .map(|last_poll| state.clock.epoch().saturating_sub(last_poll) > health.update_interval.saturating_mul(3) + 10)
+        .unwrap_or(true);
+    let healthy = health.last_error.is_none() && !overdue;
+
+    let status = if healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    let body = json!({
+        "status": if healthy { "ok" } else { "unhealthy" },
+        "last_poll": health.last_poll,
+        "last_error": health.last_error
+    });
+    (status, Json(body)).into_response()
+}
+
+async fn peers(State(state) : State<HttpState>) -> Response {
+    Json(state.view.read().unwrap().peers.clone()).into_response()
+}
+
+/// Looks a peer up by public key, with `/` encoded as `%2F`, or by friendly name.
+async fn peer(State(state) : State<HttpState>, Path(key) : Path<String>) -> Response {
+    match state.view.read().unwrap().peer(&key) {
+        Some(peer) => Json(peer.clone()).into_response(),
+        None => (StatusCode::NOT_FOUND, Json(json!({ "error": format!("No peer {} was found", key) }))).into_response()
+    }
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    /// Unix timestamp
+    #[serde(default)]
+    since : u64
+}
+
+async fn events(State(state) : State<HttpState>, Query(query) : Query<EventsQuery>) -> Response {
+    Json(state.view.read().unwrap().events_since(query.since)).into_response()
+}
+
+#[derive(Deserialize, Default)]
+struct StreamQuery {
+    /// Comma separated interfaces
+    interface : Option<String>,
+    /// Comma separated public keys or friendly names
+    peer : Option<String>,
+    /// Comma separated events, e.g. `connect,disconnect`
+    event : Option<String>,
+    /// Number of recent events sent before the live ones
+    #[serde(default)]
+    replay : usize
+}
+
+impl StreamQuery {
+    fn matches(&self, event : &DaemonEvent) -> bool {
+        let listed = |filter : &Option<String>, values : &[Option<&str>]| match filter {
+            Some(filter) => filter.split(',').map(str::trim).any(|item| values.contains(&Some(item))),
+            None => true
+        };
+        let peer = event.peer.as_ref();
+
+        listed(&self.interface, &[peer.map(|p| p.interface.as_str())])
+            && listed(&self.peer, &[peer.map(|p| p.public_key.as_str()), event.friendly_name.as_deref()])
+            && listed(&self.event, &[Some(event.event.as_str())])
+    }
+}
+
+/// Streams events as Server-Sent Events, named after the event type, starting with the latest
+/// `replay` events that match the filters.
+async fn stream(State(state) : State<HttpState>, Query(query) : Query<StreamQuery>) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    let (receiver, replay) = {
+        let view = state.view.read().unwrap();
+        let matching : Vec<DaemonEvent> = view.events_since(0).into_iter().filter(|event| query.matches(event)).collect();
+        let replay = matching[matching.len().saturating_sub(query.replay)..].to_vec();
+        (state.events.subscribe(), replay)
+    };
+
+    let live = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return Some((event, receiver)),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => warn!("Event stream fell behind, skipped {} events", skipped),
+                Err(broadcast::error::RecvError::Closed) => return None
+            }
+        }
+    });
+    let events = stream::iter(replay)
+        .chain(live.filter(move |event| std::future::ready(query.matches(event))))
+        .map(|event| {
+            let data = serde_json::to_string(&event).expect("Events can be serialised");
+            Ok(sse::Event::default().event(event.event.as_str()).data(data))
+        });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+    use crate::clock::FakeClock;
+    use crate::config::Config;
+    use crate::events::DaemonEvent;
+    use crate::metrics::Metrics;
+    use crate::notifications::Event;
+    use crate::status::peer_statuses;
+    use crate::view::View;
+    use crate::wg::parse_dump;
+    use super::{router, HttpState};
+
+    #[tokio::test]
+    async fn test_api() {
+        let dump = "wg0\tZo5RsZ9K/s8gRm3I6Au3pS1Ya7Ta8nV7NPpVXR47DHM=\t(none)\t10.2.2.68:62299\t10.2.98.3/32\t1000\t1204\t1900\t25\n";
+        let mut view = View::new(2);
+        view.peers = peer_statuses(&parse_dump(dump.to_owned()), &Config::default(), 1010);
+        view.health.last_poll = Some(1010);
+        view.health.update_interval = 5;
+        for (timestamp, event) in [(1000, Event::Connect), (1005, Event::Roam), (1010, Event::Disconnect)] {
+            view.push_event(DaemonEvent { event, peer: None, friendly_name: None, msg: String::new(), timestamp });
+        }
+
+        let state = HttpState {
+            metrics: Metrics::new(),
+            view: Arc::new(RwLock::new(view)),
+            events: tokio::sync::broadcast::channel(16).0,
+            clock: Arc::new(FakeClock::at_epoch(1012))
+        };
+        let sender = state.events.clone();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router(state)).await.unwrap() });
+
+        let get = |path : &str| reqwest::get(format!("http://{}{}", addr, path));
+        let health = get("/health").await.unwrap();
+        assert_eq!(health.status(), 200);
+
+        let peer : serde_json::Value = get("/peers/Zo5RsZ9K%2Fs8gRm3I6Au3pS1Ya7Ta8nV7NPpVXR47DHM=").await.unwrap().json().await.unwrap();
+        assert_eq!(peer["endpoint"], "10.2.2.68:62299");
+        assert_eq!(get("/peers/unknown").await.unwrap().status(), 404);
+
+        // The buffer only holds the 2 latest events
+        let events : Vec<serde_json::Value> = get("/events?since=1000").await.unwrap().json().await.unwrap();
+        let events : Vec<&str> = events.iter().map(|e| e["event"].as_str().unwrap()).collect();
+        assert_eq!(events, vec!["roam", "disconnect"]);
+
+        let mut stream = get("/events/stream?event=roam,connect&replay=5").await.unwrap();
+        let replayed = String::from_utf8(stream.chunk().await.unwrap().unwrap().to_vec()).unwrap();
+        assert!(replayed.starts_with("event: roam\n"), "{}", replayed);
+
+        for event in [Event::Disconnect, Event::Connect] {
+            sender.send(DaemonEvent { event, peer: None, friendly_name: None, msg: String::new(), timestamp: 1012 }).unwrap();
+        }
+        let live = String::from_utf8(stream.chunk().await.unwrap().unwrap().to_vec()).unwrap();
+        assert!(live.starts_with("event: connect\n"), "{}", live);
+    }
+}
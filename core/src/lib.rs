@@ -1,18 +1,52 @@
-use std::collections::HashMap;
-use std::fmt::format;
-use std::process::Command;
-use std::time::{SystemTime, UNIX_EPOCH};
-use serde_yaml::Value;
-use tracing::{debug, info};
+use std::collections::{HashMap, HashSet};
+use tracing::{debug, error, info, warn};
 use crate::config::{Config, ConfigError};
-use crate::notifications::{Event, init_providers, init_providers_map, NotificationData, ProviderError};
-use crate::wg::{get_dump, WgEntry, WgError};
+use crate::events::{DaemonEvent, EventSubscriber};
+use crate::notifications::{Event, NotificationData, PeerInfo, ProviderError};
+use crate::notifications::delivery::{DeliveryQueue, merge_batch};
+use crate::notifications::registry::ProviderRegistry;
+use crate::quiet_hours::{Deferred, QuietAction};
+use crate::routing::RouteTarget;
+use crate::sessions::{format_duration, SessionTracker};
+use crate::stability::{Debouncer, FlapDetector, FlapState};
+use chrono::{DateTime, Local, TimeZone, Utc};
+use crate::clock::{Clock, SystemClock};
+use crate::wg::{DumpSource, WgCommand, WgEntry, WgError};
 use error::Error;
+use std::future::Future;
 use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use crate::http::HttpState;
+use crate::history::SessionRecorder;
+use crate::metrics::Metrics;
+use crate::status::{format_bytes, PeerStatus};
+use crate::usage::{QuotaState, UsagePeriod, UsageTracker};
+use crate::view::{SharedView, View};
 
+pub mod check;
+pub mod clock;
 pub mod config;
+pub mod events;
+pub mod history;
+pub mod http;
 pub mod wg;
+pub mod metrics;
 pub mod notifications;
+pub mod quiet_hours;
+pub mod report;
+pub mod routing;
+pub mod schedule;
+pub mod secrets;
+pub mod sessions;
+pub mod stability;
+pub mod stale;
+pub mod status;
+pub mod unknown;
+pub mod usage;
+pub mod view;
 pub mod error;
 
 pub struct Daemon {
@@ -20,9 +54,38 @@ pub struct Daemon {
     last_handshake: HashMap<String, u64>,
     last_known_endpoint: HashMap<String, String>,
     status: HashMap<String, Status>,
+    debouncer: Debouncer,
+    flap_detector: FlapDetector,
+    sessions: SessionTracker,
+    history: Option<SessionRecorder>,
+    usage: Option<UsageTracker>,
+    /// Peers a stale peer notification has been sent for
+    stale: HashSet<String>,
+    next_digest: Option<DateTime<Utc>>,
+    /// Next time each of `conf.reports` is due
+    next_reports: Vec<DateTime<Utc>>,
+    deferred: HashMap<String, Deferred>,
+    registry: ProviderRegistry,
+    delivery: DeliveryQueue,
+    subscribers: Vec<Arc<dyn EventSubscriber>>,
+    broadcast: broadcast::Sender<DaemonEvent>,
+    tick_events: Vec<DaemonEvent>,
+    clock: Arc<dyn Clock>,
+    source: Arc<dyn DumpSource>,
+    metrics: Metrics,
+    view: SharedView,
+    http: Option<JoinHandle<()>>,
+    reload_tx: mpsc::UnboundedSender<Config>,
+    reload_rx: mpsc::UnboundedReceiver<Config>,
     conf : Config
 }
 
+fn poll_interval(secs : u64) -> tokio::time::Interval {
+    let mut interval = tokio::time::interval(Duration::from_secs(secs));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    interval
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Status {
     pub is_disconnected : bool
@@ -30,42 +93,327 @@ pub struct Status {
 
 impl Daemon {
     pub fn new(conf : Config) -> Self {
+        Self::with_registry(conf, ProviderRegistry::default())
+    }
+
+    /// Creates a daemon using the provider types of `registry`, which can contain
+    /// provider types registered by other crates.
+    pub fn with_registry(conf : Config, mut registry : ProviderRegistry) -> Self {
+        if let Err(err) = registry.load(&conf) {
+            error!("Not every notification provider could be loaded: {}", err);
+        }
+        let (reload_tx, reload_rx) = mpsc::unbounded_channel();
+        let metrics = Metrics::new();
+        let history = conf.history.as_ref().and_then(|history| match SessionRecorder::open(history) {
+            Ok(recorder) => Some(recorder),
+            Err(err) => {
+                error!("Unable to open session history {}: {}", history.path.display(), err);
+                None
+            }
+        });
+        let event_buffer = conf.http.as_ref().map(|http| http.event_buffer).unwrap_or_else(http::default_event_buffer);
+
         Self {
             entries: HashMap::new(),
             last_handshake: HashMap::new(),
             last_known_endpoint: HashMap::new(),
             status: HashMap::new(),
+            debouncer: Debouncer::new(conf.debounce.clone()),
+            flap_detector: FlapDetector::new(conf.flap_detection.clone()),
+            sessions: SessionTracker::new(SystemClock.epoch()),
+            history,
+            usage: conf.usage.clone().map(UsageTracker::new),
+            stale: HashSet::new(),
+            next_digest: conf.digest.as_ref().map(|schedule| schedule.next_after(&Utc::now())),
+            next_reports: conf.reports.iter().map(|report| report.schedule.next_after(&Utc::now())).collect(),
+            deferred: HashMap::new(),
+            delivery: DeliveryQueue::new(&conf, &registry, metrics.clone()),
+            registry,
+            subscribers: Vec::new(),
+            broadcast: broadcast::channel(1024).0,
+            tick_events: Vec::new(),
+            clock: Arc::new(SystemClock),
+            source: Arc::new(WgCommand),
+            metrics,
+            view: Arc::new(RwLock::new(View::new(event_buffer))),
+            http: None,
+            reload_tx,
+            reload_rx,
             conf,
         }
     }
 
+    /// Uses `clock` as the source of the current time, instead of the system clock.
+    pub fn with_clock(mut self, clock : Arc<dyn Clock>) -> Self {
+        let now = clock.now();
+        self.sessions = SessionTracker::new(clock.epoch());
+        self.next_digest = self.conf.digest.as_ref().map(|schedule| schedule.next_after(&now));
+        self.next_reports = self.conf.reports.iter().map(|report| report.schedule.next_after(&now)).collect();
+        self.clock = clock;
+        self
+    }
+
+    /// Reads the state of the interfaces from `source`, instead of running `wg`.
+    pub fn with_dump_source(mut self, source : Arc<dyn DumpSource>) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Registers a subscriber called with every event the daemon detects.
+    pub fn add_subscriber(&mut self, subscriber : Arc<dyn EventSubscriber>) {
+        self.subscribers.push(subscriber);
+    }
+
+    /// Returns a receiver yielding every event the daemon detects from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<DaemonEvent> {
+        self.broadcast.subscribe()
+    }
+
+    /// Returns a sender whose configs are swapped in by `run_until` between polls.
+    pub fn reloader(&self) -> mpsc::UnboundedSender<Config> {
+        self.reload_tx.clone()
+    }
+
+    /// Swaps in a new config, keeping the state of the peers, their sessions and the
+    /// notifications that are still pending.
+    ///
+    /// The providers of the new config are built first, and if any of them fails the current
+    /// config is kept. `log_level` only takes effect after a restart.
+    pub fn reload(&mut self, conf : Config) -> Result<(), ConfigError> {
+        conf.validate()?;
+        let mut registry = self.registry.clone();
+        registry.load(&conf)?;
+
+        if let Err(err) = self.delivery.reload(&conf, &registry) {
+            return Err(ConfigError::Message(err.to_string()));
+        }
+        self.debouncer.set_config(conf.debounce.clone());
+        self.flap_detector.set_config(conf.flap_detection.clone());
+        let now = self.clock.now();
+        self.next_digest = conf.digest.as_ref().map(|schedule| schedule.next_after(&now));
+        self.next_reports = conf.reports.iter().map(|report| report.schedule.next_after(&now)).collect();
+        if conf.http != self.conf.http {
+            warn!("Changes to the HTTP listener only take effect after a restart");
+        }
+        match (&mut self.usage, &conf.usage) {
+            (Some(usage), Some(usage_conf)) => usage.set_config(usage_conf.clone()),
+            (usage, usage_conf) => *usage = usage_conf.clone().map(UsageTracker::new)
+        }
+        if conf.history != self.conf.history {
+            warn!("Changes to the session history only take effect after a restart");
+        }
+        self.registry = registry;
+        self.conf = conf;
+
+        info!("Config reloaded");
+        Ok(())
+    }
+
+    /// Polls the WireGuard interfaces once, returning the events detected.
+    ///
+    /// Notifications are sent to the providers as usual, by a delivery task started on the
+    /// first call.
+    pub async fn poll_once(&mut self) -> Vec<DaemonEvent> {
+        self.delivery.spawn();
+        self.run_int().await;
+        std::mem::take(&mut self.tick_events)
+    }
+
+    /// Runs the daemon forever, blocking the current thread.
+    ///
+    /// Starts a tokio runtime to drive `run_async`, so it mustn't be called from within one.
     pub fn run(&mut self) {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("Unable to start tokio runtime");
+        runtime.block_on(self.run_async());
+    }
+
+    /// Polls the WireGuard interfaces every `update_interval` seconds forever, while
+    /// notifications are delivered concurrently by a separate task.
+    pub async fn run_async(&mut self) {
+        self.run_until(std::future::pending()).await;
+    }
+
+    /// Like `run_async`, but stops polling once `shutdown` completes and shuts down gracefully.
+    /// A poll that is in progress is finished first.
+    pub async fn run_until<F : Future<Output = ()>>(&mut self, shutdown : F) {
+        self.start_http();
+        let mut interval = poll_interval(self.conf.update_interval);
+        tokio::pin!(shutdown);
+
         loop {
-            self.run_int();
-            std::thread::sleep(std::time::Duration::from_secs(self.conf.update_interval));
+            tokio::select! {
+                _ = &mut shutdown => break,
+                Some(conf) = self.reload_rx.recv() => {
+                    let update_interval = conf.update_interval;
+                    match self.reload(conf) {
+                        Ok(()) => {
+                            if update_interval != interval.period().as_secs() {
+                                interval = poll_interval(update_interval);
+                            }
+                        },
+                        Err(err) => error!("Unable to reload config, keeping the current one: {}", err)
+                    }
+                },
+                _ = interval.tick() => {
+                    self.poll_once().await;
+                }
+            }
         }
+
+        self.shutdown().await;
     }
 
-    fn send_notification(&self, data : NotificationData) -> error::Result<()> {
-        let providers_conf = self.conf.notification_providers.clone();
-        std::thread::spawn(move || {
-            let providers = init_providers_map().unwrap();
+    /// Starts the HTTP listener if one is configured and it isn't running already.
+    pub fn start_http(&mut self) {
+        if self.http.is_some() {
+            return;
+        }
+        if let Some(http) = &self.conf.http {
+            let state = HttpState {
+                metrics: self.metrics.clone(),
+                view: self.view.clone(),
+                events: self.broadcast.clone(),
+                clock: self.clock.clone()
+            };
+            self.http = Some(tokio::spawn(http::serve(http.listen, state)));
+        }
+    }
 
-            for (key, provider) in providers {
-                if providers_conf.contains_key(&key) {
-                    if provider.enabled() {
-                        debug!("Sending notification via {} provider", key);
-                        provider.send(data.clone()).unwrap();
+    /// The view of the daemon served by the HTTP API, updated after every poll.
+    pub fn view(&self) -> SharedView {
+        self.view.clone()
+    }
+
+    /// Returns the state of every peer seen so far, as confirmed by debouncing, with the
+    /// endpoint it was last seen on.
+    pub fn peers(&self) -> Vec<PeerStatus> {
+        let now = self.clock.epoch();
+        let mut peers : Vec<PeerStatus> = self.entries.values()
+            .filter_map(|entry| match entry {
+                WgEntry::Client(data) => Some(data),
+                WgEntry::Server(_) => None
+            })
+            .map(|data| {
+                let mut peer = status::peer_status(data, &self.conf, now);
+                if let Some(status) = self.status.get(&data.public_key) {
+                    peer.connected = !status.is_disconnected;
+                }
+                peer.endpoint = self.last_known_endpoint.get(&data.public_key).cloned().or(peer.endpoint);
+                peer
+            })
+            .collect();
+        peers.sort_by(|a, b| (&a.interface, &a.public_key).cmp(&(&b.interface, &b.public_key)));
+        peers
+    }
+
+    /// The metrics of the daemon, e.g. to serve them from an HTTP server of your own.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Sends the optional "daemon stopped" notification and flushes pending notifications,
+    /// waiting at most `delivery.shutdown_timeout` seconds for them.
+    pub async fn shutdown(&mut self) {
+        info!("Shutting down wg_activity_notify");
+
+        if self.conf.notify_on_shutdown {
+            let msg = "wg_activity_notify has stopped".to_owned();
+            self.emit(Event::DaemonStopped, None, &msg, self.clock.epoch());
+            if let Err(err) = self.send_notification(NotificationData { msg, event: Event::DaemonStopped, peer: None, priority: None, severity: None }) {
+                error!("Unable to send notification: {}", err);
+            }
+        }
+
+        // Deferred notifications would otherwise be lost
+        for (provider, deferred) in std::mem::take(&mut self.deferred) {
+            if let Err(err) = self.delivery.enqueue_filtered(&provider, merge_batch(deferred.items)) {
+                error!("Unable to send deferred notifications: {}", err);
+            }
+        }
+
+        if let Some(history) = &mut self.history {
+            history.close_all(self.clock.epoch());
+        }
+        self.delivery.shutdown(Duration::from_secs(self.conf.delivery.shutdown_timeout)).await;
+        if let Some(http) = self.http.take() {
+            http.abort();
+        }
+    }
+
+    fn send_notification(&mut self, data : NotificationData) -> error::Result<()> {
+        let targets = routing::resolve(&self.conf, &data);
+        if targets.is_empty() {
+            debug!("No route matched {:?} event, not sending any notification", data.event);
+            return Ok(());
+        }
+
+        self.send_to(data, targets)
+    }
+
+    /// Sends a notification to the given targets, honouring their quiet hours.
+    fn send_to(&mut self, data : NotificationData, targets : Vec<RouteTarget>) -> error::Result<()> {
+        let now = self.clock.now();
+        for target in targets {
+            if !self.registry.contains(&target.provider) {
+                warn!("Route refers to unknown provider {}", target.provider);
+                continue;
+            }
+
+            let mut data = data.clone();
+            data.priority = target.priority;
+            data.severity = target.severity;
+
+            let mut rules = target.quiet_hours;
+            if let Some(provider) = self.registry.get(&target.provider) {
+                rules.extend(provider.settings.quiet_hours.iter().cloned());
+            }
+
+            match quiet_hours::active(&rules, &data.event, &now).map(|rule| (rule.action.clone(), rule.severity)) {
+                None => self.delivery.enqueue(&target.provider, data)?,
+                Some((QuietAction::Mute, _)) => debug!("Quiet hours, muting notification via {}", target.provider),
+                Some((QuietAction::Downgrade, severity)) => {
+                    debug!("Quiet hours, downgrading notification via {} to {:?}", target.provider, severity);
+                    data.priority = None;
+                    data.severity = Some(severity);
+                    self.delivery.enqueue(&target.provider, data)?;
+                },
+                Some((QuietAction::Defer, _)) => {
+                    // The summary is sent as a digest, so filter on the events it's made of instead
+                    if !self.registry.get(&target.provider).is_some_and(|provider| provider.accepts(&data.event)) {
+                        debug!("Provider {} doesn't accept {:?} events", target.provider, data.event);
+                        continue;
                     }
+                    debug!("Quiet hours, deferring notification via {}", target.provider);
+                    let deferred = self.deferred.entry(target.provider.clone()).or_default();
+                    deferred.rules = rules;
+                    deferred.items.push(data);
                 }
             }
-        });
+        }
 
         Ok(())
     }
 
+    /// Sends a summary of the notifications deferred for providers whose quiet hours have ended.
+    fn flush_deferred(&mut self) {
+        let now = self.clock.now();
+        let ended : Vec<String> = self.deferred.iter().filter(|(_, d)| !d.is_active(&now)).map(|(k, _)| k.clone()).collect();
+
+        for provider in ended {
+            if let Some(deferred) = self.deferred.remove(&provider) {
+                debug!("Quiet hours ended, sending {} deferred notifications via {}", deferred.items.len(), provider);
+                if let Err(err) = self.delivery.enqueue_filtered(&provider, merge_batch(deferred.items)) {
+                    error!("Unable to send deferred notifications: {}", err);
+                }
+            }
+        }
+    }
+
     fn get_friendly_name(&self, pub_key : &str) -> String {
-        return match self.conf.friendly_names.get(pub_key) {
+        match self.conf.friendly_names.get(pub_key) {
             None => pub_key.to_owned(),
             Some(val) => format!("{val} ({pub_key})")
         }
@@ -75,15 +423,7 @@ impl Daemon {
         let mut status = Status::default();
 
         if let WgEntry::Client(data) = entry {
-            let current_epoch = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time is an illusion").as_secs();
-            let handshake_threshold = data.persistent_keepalive * 7;
-            let seconds_since_last_handshake = current_epoch - data.latest_handshake;
-
-
-            if seconds_since_last_handshake > handshake_threshold {
-                status.is_disconnected = true;
-            }
-
+            status.is_disconnected = !status::is_connected(data, self.clock.epoch());
             return Ok(status);
         };
 
@@ -100,56 +440,653 @@ impl Daemon {
         false
     }
 
-    fn run_int(&mut self) {
+    fn emit(&mut self, event : Event, peer : Option<&PeerInfo>, msg : &str, now : u64) {
+        let event = DaemonEvent {
+            event,
+            peer: peer.cloned(),
+            friendly_name: peer.and_then(|p| self.conf.friendly_names.get(&p.public_key).cloned()),
+            msg: msg.to_owned(),
+            timestamp: now
+        };
+
+        self.metrics.event(&event.event);
+        for subscriber in &self.subscribers {
+            subscriber.on_event(&event);
+        }
+        // Broadcast while holding the lock, so streams replaying the view don't see it twice
+        let mut view = self.view.write().unwrap();
+        view.push_event(event.clone());
+        // Sending only fails when there are no receivers, which is fine
+        let _ = self.broadcast.send(event.clone());
+        drop(view);
+        self.tick_events.push(event);
+    }
+
+    fn handle_transition(&mut self, event : Event, peer : &PeerInfo, friendly_name : &str, data_ip : &str, now : u64) {
+        let verb = match event {
+            Event::Disconnect => "disconnected",
+            _ => "connected"
+        };
+        let msg = format!("Client {} using endpoint {} has {}", friendly_name, data_ip, verb);
+        info!("{}", msg);
+        self.emit(event.clone(), Some(peer), &msg, now);
+
+        match self.flap_detector.record(&peer.public_key, now) {
+            FlapState::Stable => self.notify(msg, event, peer, data_ip),
+            FlapState::StartedFlapping(changes) => {
+                let msg = format!("Client {} using endpoint {} is flapping, it changed state {} times within {}s", friendly_name, data_ip, changes, self.conf.flap_detection.window);
+                warn!("{}", msg);
+                self.emit(Event::Flapping, Some(peer), &msg, now);
+                self.notify(msg, Event::Flapping, peer, data_ip);
+            },
+            FlapState::Flapping => debug!("Client {} is flapping, suppressing notification", friendly_name)
+        }
+    }
+
+    fn handle_quota(&mut self, state : QuotaState, peer : &PeerInfo, friendly_name : &str, data_ip : &str, now : u64) {
+        let period = match self.conf.usage.as_ref().map(|usage| &usage.period) {
+            Some(UsagePeriod::Day) => "daily",
+            _ => "monthly"
+        };
+        let (event, msg) = match state {
+            QuotaState::Warning { used, limit } => (Event::QuotaWarning,
+                format!("Client {} has used {} of its {} {} quota", friendly_name, format_bytes(used as i64), format_bytes(limit as i64), period)),
+            QuotaState::Exceeded { used, limit } => (Event::QuotaExceeded,
+                format!("Client {} has exceeded its {} {} quota, using {}", friendly_name, format_bytes(limit as i64), period, format_bytes(used as i64)))
+        };
+        warn!("{}", msg);
+        self.emit(event.clone(), Some(peer), &msg, now);
+        self.notify(msg, event, peer, data_ip);
+    }
+
+    /// Alerts about a connected peer that isn't in the inventory, if `unknown_peers` is set.
+    fn check_unknown(&mut self, peer : &PeerInfo, data_ip : &str, now : u64) {
+        if self.conf.unknown_peers.is_none() || unknown::is_known(&self.conf, &peer.public_key) {
+            return;
+        }
+
+        let msg = format!("Unknown client {} connected from endpoint {}", peer.public_key, data_ip);
+        warn!("{}", msg);
+        self.emit(Event::UnknownPeer, Some(peer), &msg, now);
+        // Not subject to `ignored_subnets`, an unknown key is worth knowing about wherever it connects from
+        if let Err(err) = self.send_notification(NotificationData { msg, event: Event::UnknownPeer, peer: Some(peer.clone()), priority: None, severity: None }) {
+            error!("Unable to send notification: {}", err);
+        }
+    }
+
+    fn notify(&mut self, msg : String, event : Event, peer : &PeerInfo, data_ip : &str) {
+        if self.should_ignore(&Some(data_ip.to_owned())) {
+            return;
+        }
+
+        if let Err(err) = self.send_notification(NotificationData { msg, event, peer: Some(peer.clone()), priority: None, severity: None }) {
+            error!("Unable to send notification: {}", err);
+        }
+    }
+
+    fn check_digest(&mut self, now : u64) {
+        let (schedule, due) = match (&self.conf.digest, self.next_digest) {
+            (Some(schedule), Some(due)) => (schedule, due),
+            _ => return
+        };
+
+        let utc_now = self.clock.now();
+        if utc_now < due {
+            return;
+        }
+        self.next_digest = Some(schedule.next_after(&utc_now));
+
+        let period_start = match Local.timestamp_opt(self.sessions.period_start() as i64, 0) {
+            chrono::LocalResult::Single(val) => val.format("%Y-%m-%d %H:%M").to_string(),
+            _ => "?".to_owned()
+        };
+
+        let mut peers : Vec<(&String, &sessions::PeerSessions)> = self.sessions.peers().iter().collect();
+        peers.sort_by_key(|(key, _)| self.get_friendly_name(key));
+        let lines : Vec<String> = peers.iter().map(|(key, peer)| {
+            format!("{}: {} session{}, connected for {}", self.get_friendly_name(key), peer.sessions, if peer.sessions == 1 { "" } else { "s" },
+                    format_duration(peer.total_connected_secs(now, self.sessions.period_start())))
+        }).collect();
+
+        let msg = if lines.is_empty() {
+            format!("No client sessions since {}", period_start)
+        } else {
+            format!("Sessions since {}:\n{}", period_start, lines.join("\n"))
+        };
+        info!("{}", msg);
+        self.emit(Event::Digest, None, &msg, now);
+        self.sessions.reset(now);
+
+        if let Err(err) = self.send_notification(NotificationData { msg, event: Event::Digest, peer: None, priority: None, severity: None }) {
+            error!("Unable to send digest: {}", err);
+        }
+    }
+
+    fn check_reports(&mut self, now : u64) {
+        let utc_now = self.clock.now();
+
+        for idx in 0..self.conf.reports.len() {
+            let report = &self.conf.reports[idx];
+            if utc_now < self.next_reports[idx] {
+                continue;
+            }
+            self.next_reports[idx] = report.schedule.next_after(&utc_now);
+
+            let from = now.saturating_sub(report.period());
+            let sessions = match &self.history {
+                Some(history) => match history.sessions_since(from, now) {
+                    Ok(sessions) => Some(sessions),
+                    Err(err) => {
+                        error!("Unable to read session history for the report: {}", err);
+                        None
+                    }
+                },
+                None => None
+            };
+            let peers = report::build(report, &self.peers(), sessions.as_deref(), from, now);
+            let msg = report::format(report, &peers, from, now);
+            let targets : Vec<RouteTarget> = report.providers.iter()
+                .map(|provider| RouteTarget { provider: provider.clone(), priority: None, severity: None, quiet_hours: Vec::new() })
+                .collect();
+
+            info!("{}", msg);
+            self.emit(Event::Report, None, &msg, now);
+            let data = NotificationData { msg, event: Event::Report, peer: None, priority: None, severity: None };
+            let result = if targets.is_empty() { self.send_notification(data) } else { self.send_to(data, targets) };
+            if let Err(err) = result {
+                error!("Unable to send report: {}", err);
+            }
+        }
+    }
+
+    async fn run_int(&mut self) {
         debug!("Checking WireGuard clients");
+        let now = self.clock.epoch();
+        let local_now = self.clock.now().with_timezone(&Local);
+
+        let started = std::time::Instant::now();
+        let dump = self.source.dump().await;
+        self.metrics.poll(started.elapsed(), dump.is_err());
+        let entries = match dump {
+            Ok(entries) => entries,
+            Err(err) => {
+                error!("Unable to read WireGuard state: {}", err);
+                self.view.write().unwrap().health.last_error = Some(err.to_string());
+                return;
+            }
+        };
+        self.metrics.observe_peers(&status::peer_statuses(&entries, &self.conf, now));
+
+        // Forget the peers removed from the interfaces, so they don't linger in the view
+        let present : HashSet<String> = entries.iter().filter_map(|entry| match entry {
+            WgEntry::Client(data) => Some(data.public_key.clone()),
+            WgEntry::Server(_) => None
+        }).collect();
+        let removed : Vec<String> = self.entries.keys().filter(|key| !present.contains(*key)).cloned().collect();
+        for key in &removed {
+            debug!("Client {} was removed", key);
+            if let (Some(history), Some(WgEntry::Client(data))) = (&mut self.history, self.entries.get(key)) {
+                history.disconnected(data, now);
+            }
+            self.sessions.disconnected(key, now);
+            self.debouncer.forget(key);
+            self.flap_detector.forget(key);
+        }
+        self.entries.retain(|key, _| present.contains(key));
+        self.status.retain(|key, _| present.contains(key));
+        self.last_handshake.retain(|key, _| present.contains(key));
+        self.last_known_endpoint.retain(|key, _| present.contains(key));
+        self.stale.retain(|key| present.contains(key));
+
+        let mut stale_peers_found = Vec::new();
 
-        let entries = get_dump();
         for entry in &entries {
             if let WgEntry::Client(data) = entry {
                 self.entries.insert(data.public_key.clone(), entry.clone());
 
                 let mut data_ip = "?".to_owned();
+                let mut known_endpoint = None;
+                let previous_endpoint = self.last_known_endpoint.get(&data.public_key).cloned();
 
                 if let Some(endpoint) = &data.endpoint {
                     self.last_known_endpoint.insert(data.public_key.clone(), endpoint.clone());
                     data_ip = endpoint.clone();
-                } else {
-                    if let Some (endpoint) = self.last_known_endpoint.get(&data.public_key) {
-                        data_ip = endpoint.clone();
-                    }
+                    known_endpoint = Some(endpoint.clone());
+                } else if let Some (endpoint) = self.last_known_endpoint.get(&data.public_key) {
+                    data_ip = endpoint.clone();
+                    known_endpoint = Some(endpoint.clone());
                 }
 
-                let current_status = self.status_of_entry(entry).unwrap();
-                let previous_status = self.status.get(&data.public_key);
+                let peer = PeerInfo {
+                    public_key: data.public_key.clone(),
+                    interface: data.interface.clone(),
+                    endpoint: known_endpoint
+                };
+
+                let observed_status = self.status_of_entry(entry).unwrap();
+                let previous_status = self.status.get(&data.public_key).cloned();
                 let friendly_name = self.get_friendly_name(&data.public_key);
 
-                if current_status.is_disconnected {
-                    if let Some(s) = previous_status {
-                        if current_status.is_disconnected != s.is_disconnected { // Reached if current is_disconnected is true & the previous status is not
-                            let msg = format!("Client {} using endpoint {} has disconnected", friendly_name, data_ip);
-                            info!("{}", msg);
-                            if !self.should_ignore(&Some(data_ip.clone())) {
-                                self.send_notification(NotificationData { msg, event: Event::Disconnect });
+                let current_status = match &previous_status {
+                    Some(s) => self.debouncer.confirm(&data.public_key, s, &observed_status, now),
+                    None => observed_status
+                };
+
+                match &previous_status {
+                    Some(s) => {
+                        if current_status.is_disconnected != s.is_disconnected {
+                            let event = if current_status.is_disconnected {
+                                self.sessions.disconnected(&data.public_key, now);
+                                if let Some(history) = &mut self.history {
+                                    history.observe(data);
+                                    history.disconnected(data, now);
+                                }
+                                Event::Disconnect
+                            } else {
+                                self.sessions.connected(&data.public_key, now);
+                                if let Some(history) = &mut self.history {
+                                    history.connected(data, now);
+                                }
+                                Event::Connect
+                            };
+                            let connected = event == Event::Connect;
+                            self.handle_transition(event, &peer, &friendly_name, &data_ip, now);
+                            if connected {
+                                self.check_unknown(&peer, &data_ip, now);
                             }
                         }
-                    }
-                } else {
-                    if let Some(s) = previous_status {
-                        if current_status.is_disconnected != s.is_disconnected { // Reached if current is_disconnected is false & the previous status is not
-                            let msg = format!("Client {} using endpoint {} has connected", friendly_name, data_ip);
-                            info!("{}", msg);
-                            if !self.should_ignore(&Some(data_ip.clone())) {
-                                self.send_notification(NotificationData { msg, event: Event::Connect });
+                    },
+                    None => {
+                        if !current_status.is_disconnected {
+                            self.sessions.connected(&data.public_key, now);
+                            if let Some(history) = &mut self.history {
+                                history.connected(data, now);
                             }
+                            self.check_unknown(&peer, &data_ip, now);
+                        }
+                    }
+                }
+                if let Some(history) = &mut self.history {
+                    history.observe(data);
+                }
+                let quota = match &mut self.usage {
+                    Some(usage) => usage.observe(data, self.conf.friendly_names.get(&data.public_key).map(String::as_str), &local_now),
+                    None => None
+                };
+                if let Some(state) = quota {
+                    self.handle_quota(state, &peer, &friendly_name, &data_ip, now);
+                }
+
+                if let Some(stale_peers) = &self.conf.stale_peers {
+                    if !stale::is_stale(data, now, stale_peers.after) {
+                        self.stale.remove(&data.public_key);
+                    } else if self.stale.insert(data.public_key.clone()) {
+                        let msg = format!("Client {} {}", friendly_name, stale::describe(data, now));
+                        info!("{}", msg);
+                        self.emit(Event::StalePeer, Some(&peer), &msg, now);
+                        if !self.should_ignore(&Some(data_ip.clone())) {
+                            stale_peers_found.push(NotificationData { msg, event: Event::StalePeer, peer: Some(peer.clone()), priority: None, severity: None });
                         }
                     }
                 }
 
+                if let (Some(s), Some(previous), Some(current)) = (&previous_status, &previous_endpoint, &data.endpoint) {
+                    if !s.is_disconnected && !current_status.is_disconnected && previous != current {
+                        let msg = format!("Client {} roamed from endpoint {} to {}", friendly_name, previous, current);
+                        info!("{}", msg);
+                        self.emit(Event::Roam, Some(&peer), &msg, now);
+                        if self.conf.notify_roaming {
+                            self.notify(msg, Event::Roam, &peer, &data_ip);
+                        }
+                    }
+                }
+
+                if let Some(suppressed) = self.flap_detector.check_stopped(&data.public_key, now) {
+                    let state = if current_status.is_disconnected { "disconnected" } else { "connected" };
+                    let msg = format!("Client {} using endpoint {} is no longer flapping, {} changes were suppressed. It is currently {}", friendly_name, data_ip, suppressed, state);
+                    info!("{}", msg);
+                    self.emit(Event::FlappingStopped, Some(&peer), &msg, now);
+                    self.notify(msg, Event::FlappingStopped, &peer, &data_ip);
+                }
+
                 // Update last_handshake & status
                 self.last_handshake.insert(data.public_key.clone(), data.latest_handshake);
                 self.status.insert(data.public_key.clone(), current_status.clone());
             }
         }
 
+        // Sent as one notification, as every stale peer is found again after a restart
+        if !stale_peers_found.is_empty() {
+            if let Err(err) = self.send_notification(merge_batch(stale_peers_found)) {
+                error!("Unable to send notification: {}", err);
+            }
+        }
+
+        self.check_digest(now);
+        self.check_reports(now);
+        self.flush_deferred();
+        if let Some(history) = &mut self.history {
+            history.prune(now);
+        }
+        if let Some(usage) = &mut self.usage {
+            usage.persist();
+        }
+
+        let peers = self.peers();
+        let mut view = self.view.write().unwrap();
+        view.peers = peers;
+        view.health.last_poll = Some(now);
+        view.health.last_error = None;
+        view.health.update_interval = self.conf.update_interval;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use async_trait::async_trait;
+    use crate::clock::FakeClock;
+    use crate::config::Config;
+    use crate::notifications::Event;
+    use crate::notifications::registry::ProviderRegistry;
+    use crate::notifications::testing::{wait_for_sent, ScriptedFactory, ScriptedHandler};
+    use crate::wg::{parse_dump, DumpSource, WgEntry, WgError};
+    use super::Daemon;
+
+    const START : i64 = 1_700_000_000;
+    const LAPTOP : &str = "QXNodG9uIFNoZXJ5bCBNb3JzZQ==";
+
+    /// Hands out one scripted dump per poll.
+    struct ScriptedDump {
+        dumps : Mutex<VecDeque<String>>
+    }
+
+    #[async_trait]
+    impl DumpSource for ScriptedDump {
+        async fn dump(&self) -> Result<Vec<WgEntry>, WgError> {
+            let dump = self.dumps.lock().unwrap().pop_front().expect("No dump left");
+            Ok(parse_dump(dump))
+        }
+    }
+
+    fn peer_line(endpoint : &str, latest_handshake : i64) -> String {
+        format!("wg0\t{}\t(none)\t{}\t10.2.98.3/32\t{}\t1204\t1900\t25\n", LAPTOP, endpoint, latest_handshake)
+    }
+
+    fn daemon(dumps : Vec<String>) -> (Daemon, FakeClock) {
+        let clock = FakeClock::at_epoch(START);
+        let source = ScriptedDump { dumps: Mutex::new(dumps.into()) };
+        let daemon = Daemon::new(Config::default())
+            .with_clock(Arc::new(clock.clone()))
+            .with_dump_source(Arc::new(source));
+        (daemon, clock)
+    }
+
+    fn events(events : &[crate::events::DaemonEvent]) -> Vec<Event> {
+        events.iter().map(|e| e.event.clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_startup_emits_nothing() {
+        let never_connected = "wg0\tdW5kZSBleC4gUXVhcw==\t(none)\t(none)\t10.2.98.6/32\t0\t0\t0\t25\n";
+        let (mut daemon, clock) = daemon(vec![
+            peer_line("10.2.2.68:62299", START - 10) + never_connected,
+            peer_line("10.2.2.68:62299", START) + never_connected,
+        ]);
+
+        assert!(daemon.poll_once().await.is_empty());
+        clock.advance(5);
+        assert!(daemon.poll_once().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_and_disconnect() {
+        let (mut daemon, clock) = daemon(vec![
+            peer_line("(none)", 0),
+            peer_line("10.2.2.68:62299", START + 5),
+            peer_line("10.2.2.68:62299", START + 5),
+        ]);
+
+        assert!(daemon.poll_once().await.is_empty());
+
+        clock.advance(5);
+        let connected = daemon.poll_once().await;
+        assert_eq!(events(&connected), vec![Event::Connect]);
+        assert_eq!(connected[0].peer.as_ref().unwrap().public_key, LAPTOP);
+        assert_eq!(connected[0].timestamp, (START + 5) as u64);
+
+        // Keepalive is 25s, so a peer is considered disconnected after 175s without a handshake
+        clock.advance(176);
+        assert_eq!(events(&daemon.poll_once().await), vec![Event::Disconnect]);
+    }
+
+    #[tokio::test]
+    async fn test_removed_peer() {
+        let other = "wg0\tdW5kZSBleC4gUXVhcw==\t(none)\t(none)\t10.2.98.6/32\t0\t0\t0\t25\n";
+        let (mut daemon, _) = daemon(vec![peer_line("10.2.2.68:62299", START) + other, other.to_owned()]);
+
+        daemon.poll_once().await;
+        assert_eq!(daemon.peers().len(), 2);
+
+        daemon.poll_once().await;
+        let peers = daemon.peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].public_key, "dW5kZSBleC4gUXVhcw==");
+    }
+
+    #[tokio::test]
+    async fn test_removed_connected_peer() {
+        let source = ScriptedDump { dumps: Mutex::new(vec![
+            peer_line("10.2.2.68:62299", START),
+            String::new(),
+        ].into()) };
+        let conf : Config = serde_yaml::from_str("history:\n  path: \":memory:\"\n").unwrap();
+        let clock = FakeClock::at_epoch(START);
+        let mut daemon = Daemon::new(conf)
+            .with_clock(Arc::new(clock.clone()))
+            .with_dump_source(Arc::new(source));
+
+        daemon.poll_once().await;
+        clock.advance(60);
+        daemon.poll_once().await;
+
+        // The session ends with the peer's latest handshake instead of staying open
+        assert!(daemon.sessions.peers()[LAPTOP].connected_since.is_none());
+        let history = daemon.history.as_ref().unwrap();
+        let sessions = history.db().query(&crate::history::SessionQuery::default()).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!((sessions[0].started, sessions[0].ended), (START as u64, START as u64));
+        assert_eq!(history.sessions_since(0, START as u64 + 60).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stale_peer() {
+        let other = "wg0\tdW5kZSBleC4gUXVhcw==\t(none)\t(none)\t10.2.98.6/32\t0\t0\t0\t25\n";
+        let source = ScriptedDump { dumps: Mutex::new(vec![
+            peer_line("(none)", 0) + other,
+            peer_line("(none)", 0) + other,
+            peer_line("10.2.2.68:62299", START) + other,
+        ].into()) };
+        let handler = ScriptedHandler::failing(Vec::new());
+        let mut registry = ProviderRegistry::default();
+        registry.register(ScriptedFactory(handler.clone()));
+        let conf : Config = serde_yaml::from_str("
+notification_providers:
+  pager:
+    type: scripted
+    enable: true
+    events: [stale_peer]
+stale_peers: {}
+").unwrap();
+        let mut daemon = Daemon::with_registry(conf, registry)
+            .with_clock(Arc::new(FakeClock::at_epoch(START)))
+            .with_dump_source(Arc::new(source));
+
+        let stale = daemon.poll_once().await;
+        assert_eq!(events(&stale), vec![Event::StalePeer, Event::StalePeer]);
+        assert!(stale[0].msg.ends_with("has never connected"));
+        assert!(daemon.poll_once().await.is_empty());
+        assert_eq!(events(&daemon.poll_once().await), vec![Event::Connect]);
+
+        // Both stale peers are in a single notification
+        let sent = wait_for_sent(&handler, 1).await;
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].msg.starts_with("2 clients are stale:"));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_unknown_peer() {
+        let source = ScriptedDump { dumps: Mutex::new(vec![
+            peer_line("(none)", 0),
+            peer_line("10.2.2.68:62299", START),
+        ].into()) };
+        let handler = ScriptedHandler::failing(Vec::new());
+        let mut registry = ProviderRegistry::default();
+        registry.register(ScriptedFactory(handler.clone()));
+        let conf : Config = serde_yaml::from_str("
+notification_providers:
+  pager:
+    type: scripted
+    enable: true
+unknown_peers: {}
+ignored_subnets: [10.2.2.0/24]
+").unwrap();
+        let mut daemon = Daemon::with_registry(conf, registry)
+            .with_clock(Arc::new(FakeClock::at_epoch(START)))
+            .with_dump_source(Arc::new(source));
+
+        assert!(daemon.poll_once().await.is_empty());
+        let connected = daemon.poll_once().await;
+        assert_eq!(events(&connected), vec![Event::Connect, Event::UnknownPeer]);
+        assert_eq!(Event::UnknownPeer.default_severity(), Some(crate::notifications::Severity::High));
+
+        // Only the connect is silenced by the ignored subnet
+        let sent = wait_for_sent(&handler, 1).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].event, Event::UnknownPeer);
+    }
+
+    #[tokio::test]
+    async fn test_roaming() {
+        let (mut daemon, clock) = daemon(vec![
+            peer_line("10.2.2.68:62299", START),
+            peer_line("192.0.2.10:51820", START + 5),
+        ]);
+
+        assert!(daemon.poll_once().await.is_empty());
+
+        clock.advance(5);
+        let roamed = daemon.poll_once().await;
+        assert_eq!(events(&roamed), vec![Event::Roam]);
+        assert_eq!(roamed[0].peer.as_ref().unwrap().endpoint.as_deref(), Some("192.0.2.10:51820"));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_in_the_future() {
+        let (mut daemon, _) = daemon(vec![
+            peer_line("10.2.2.68:62299", START + 3600),
+            peer_line("10.2.2.68:62299", START + 3600),
+        ]);
+
+        assert!(daemon.poll_once().await.is_empty());
+        assert!(daemon.poll_once().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_peer_state() {
+        let (mut daemon, clock) = daemon(vec![
+            peer_line("10.2.2.68:62299", START),
+            peer_line("10.2.2.68:62299", START - 200),
+        ]);
+        assert!(daemon.poll_once().await.is_empty());
+
+        let invalid : Config = serde_yaml::from_str("notification_providers:\n  discord: {}\n").unwrap();
+        assert!(daemon.reload(invalid).is_err());
+        assert!(daemon.conf.notification_providers.is_empty());
+
+        // Polling every 0 seconds isn't possible, so the config is kept
+        assert!(daemon.reload(Config { update_interval: 0, ..Default::default() }).is_err());
+
+        let mut conf = Config::default();
+        conf.friendly_names.insert(LAPTOP.to_owned(), "laptop".to_owned());
+        daemon.reload(conf).unwrap();
+
+        // Still known as connected, so the disconnect is detected
+        clock.advance(5);
+        let disconnected = daemon.poll_once().await;
+        assert_eq!(events(&disconnected), vec![Event::Disconnect]);
+        assert_eq!(disconnected[0].friendly_name.as_deref(), Some("laptop"));
+    }
+
+    #[tokio::test]
+    async fn test_deferred_summary() {
+        let handler = ScriptedHandler::failing(Vec::new());
+        let mut registry = ProviderRegistry::default();
+        registry.register(ScriptedFactory(handler.clone()));
+        let conf : Config = serde_yaml::from_str(r#"
+notification_providers:
+  pager:
+    type: scripted
+    enable: true
+    events: [connect, disconnect]
+    quiet_hours:
+      - from: "22:00"
+        to: "07:00"
+        timezone: UTC
+        action: defer
+notify_roaming: true
+"#).unwrap();
+
+        // START is 22:13 UTC, within the quiet hours
+        let clock = FakeClock::at_epoch(START);
+        let source = ScriptedDump { dumps: Mutex::new(vec![
+            peer_line("(none)", 0),
+            peer_line("10.2.2.68:62299", START + 5),
+            peer_line("192.0.2.10:51820", START + 10),
+            peer_line("192.0.2.10:51820", START + 10),
+            peer_line("192.0.2.10:51820", START + 10),
+        ].into()) };
+        let mut daemon = Daemon::with_registry(conf, registry)
+            .with_clock(Arc::new(clock.clone()))
+            .with_dump_source(Arc::new(source));
+
+        daemon.poll_once().await;
+        clock.advance(5);
+        assert_eq!(events(&daemon.poll_once().await), vec![Event::Connect]);
+        clock.advance(5);
+        assert_eq!(events(&daemon.poll_once().await), vec![Event::Roam]);
+        clock.advance(176);
+        assert_eq!(events(&daemon.poll_once().await), vec![Event::Disconnect]);
+        assert!(handler.sent().is_empty());
+
+        // 08:00 the next day
+        clock.set(chrono::DateTime::from_timestamp(START + 35_200, 0).unwrap());
+        daemon.poll_once().await;
+        let sent = wait_for_sent(&handler, 1).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].event, Event::Digest);
+        // Roaming isn't accepted by the provider, so it's left out of the summary
+        assert!(sent[0].msg.starts_with("1 client connected, 1 client disconnected:"));
+        assert!(!sent[0].msg.contains("roamed"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let (mut daemon, clock) = daemon(vec![
+            peer_line("(none)", 0),
+            peer_line("10.2.2.68:62299", START + 5),
+        ]);
+
+        daemon.poll_once().await;
+        clock.advance(5);
+        daemon.poll_once().await;
+
+        let metrics = daemon.metrics().encode();
+        let labels = format!("friendly_name=\"\",interface=\"wg0\",public_key=\"{}\"", LAPTOP);
+        assert!(metrics.contains(&format!("wg_activity_notify_peer_connected{{{}}} 1", labels)), "{}", metrics);
+        assert!(metrics.contains(&format!("wg_activity_notify_peer_receive_bytes{{{}}} 1204", labels)));
+        assert!(metrics.contains("wg_activity_notify_events_total{event=\"connect\"} 1"));
+        assert!(metrics.contains("wg_activity_notify_poll_duration_seconds_count 2"));
+    }
+}
@@ -0,0 +1,109 @@
+use std::time::Duration;
+use prometheus::{Encoder, GaugeVec, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry, TextEncoder};
+use crate::notifications::Event;
+use crate::status::PeerStatus;
+
+const PEER_LABELS : [&str; 3] = ["interface", "public_key", "friendly_name"];
+
+/// Prometheus metrics of a daemon. Clones share the same metrics.
+#[derive(Clone)]
+pub struct Metrics {
+    registry : Registry,
+    peer_connected : GaugeVec,
+    peer_handshake_age : GaugeVec,
+    peer_receive_bytes : GaugeVec,
+    peer_transmit_bytes : GaugeVec,
+    peer_keepalive : GaugeVec,
+    events : IntCounterVec,
+    notifications_sent : IntCounterVec,
+    notifications_failed : IntCounterVec,
+    poll_duration : Histogram,
+    poll_errors : IntCounter
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("wg_activity_notify".to_owned()), None)
+            .expect("Metric prefix is valid");
+        let peer_gauge = |name : &str, help : &str| {
+            let gauge = GaugeVec::new(Opts::new(name, help), &PEER_LABELS).expect("Metric is valid");
+            registry.register(Box::new(gauge.clone())).expect("Metric is registered once");
+            gauge
+        };
+        let counter = |name : &str, help : &str, label : &str| {
+            let counter = IntCounterVec::new(Opts::new(name, help), &[label]).expect("Metric is valid");
+            registry.register(Box::new(counter.clone())).expect("Metric is registered once");
+            counter
+        };
+
+        let metrics = Self {
+            peer_connected: peer_gauge("peer_connected", "Whether the peer is connected"),
+            peer_handshake_age: peer_gauge("peer_last_handshake_seconds", "Seconds since the latest handshake of the peer"),
+            peer_receive_bytes: peer_gauge("peer_receive_bytes", "Bytes received from the peer, as reported by wg"),
+            peer_transmit_bytes: peer_gauge("peer_transmit_bytes", "Bytes sent to the peer, as reported by wg"),
+            peer_keepalive: peer_gauge("peer_persistent_keepalive_seconds", "Persistent keepalive interval of the peer, 0 if off"),
+            events: counter("events_total", "Events emitted", "event"),
+            notifications_sent: counter("notifications_sent_total", "Notifications sent", "provider"),
+            notifications_failed: counter("notifications_failed_total", "Failed attempts at sending a notification", "provider"),
+            poll_duration: Histogram::with_opts(HistogramOpts::new("poll_duration_seconds", "Duration of reading the state of the interfaces"))
+                .expect("Metric is valid"),
+            poll_errors: IntCounter::new("poll_errors_total", "Failures to read the state of the interfaces")
+                .expect("Metric is valid"),
+            registry
+        };
+        metrics.registry.register(Box::new(metrics.poll_duration.clone())).expect("Metric is registered once");
+        metrics.registry.register(Box::new(metrics.poll_errors.clone())).expect("Metric is registered once");
+
+        metrics
+    }
+
+    /// Replaces the peer gauges with the state of `peers`, dropping peers that are gone.
+    pub fn observe_peers(&self, peers : &[PeerStatus]) {
+        for gauge in [&self.peer_connected, &self.peer_handshake_age, &self.peer_receive_bytes, &self.peer_transmit_bytes, &self.peer_keepalive] {
+            gauge.reset();
+        }
+
+        for peer in peers {
+            let labels = [peer.interface.as_str(), peer.public_key.as_str(), peer.friendly_name.as_deref().unwrap_or("")];
+            self.peer_connected.with_label_values(&labels).set(if peer.connected { 1.0 } else { 0.0 });
+            if let Some(age) = peer.handshake_age {
+                self.peer_handshake_age.with_label_values(&labels).set(age as f64);
+            }
+            self.peer_receive_bytes.with_label_values(&labels).set(peer.transfer_rx as f64);
+            self.peer_transmit_bytes.with_label_values(&labels).set(peer.transfer_tx as f64);
+            self.peer_keepalive.with_label_values(&labels).set(peer.persistent_keepalive as f64);
+        }
+    }
+
+    pub fn event(&self, event : &Event) {
+        self.events.with_label_values(&[event.as_str()]).inc();
+    }
+
+    pub fn notification_sent(&self, provider : &str) {
+        self.notifications_sent.with_label_values(&[provider]).inc();
+    }
+
+    pub fn notification_failed(&self, provider : &str) {
+        self.notifications_failed.with_label_values(&[provider]).inc();
+    }
+
+    pub fn poll(&self, duration : Duration, failed : bool) {
+        self.poll_duration.observe(duration.as_secs_f64());
+        if failed {
+            self.poll_errors.inc();
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf).expect("Metrics can be encoded");
+        String::from_utf8(buf).expect("Metrics are valid UTF-8")
+    }
+}
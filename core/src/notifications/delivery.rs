@@ -0,0 +1,796 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use serde::{Serialize, Deserialize};
+use tracing::{debug, error, warn};
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::metrics::Metrics;
+use crate::notifications::{Event, NotificationData, Provider, ProviderError, Severity};
+use crate::notifications::registry::ProviderRegistry;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(from = "RawDeliveryConfig")]
+pub struct DeliveryConfig {
+    #[serde(flatten)]
+    pub retry : RetryConfig,
+    /// File pending notifications are persisted to, so they survive a restart
+    #[serde(default)]
+    pub outbox : Option<PathBuf>,
+    /// Seconds a single attempt may take
+    #[serde(default = "default_timeout")]
+    pub timeout : u64,
+    /// Seconds to wait for pending notifications when shutting down
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout : u64
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> Self {
+        Self {
+            retry: RetryConfig::default(),
+            outbox: None,
+            timeout: default_timeout(),
+            shutdown_timeout: default_shutdown_timeout()
+        }
+    }
+}
+
+/// `DeliveryConfig` as written in the config. serde doesn't support `deny_unknown_fields`
+/// along with `flatten`, so the retry policy is spelled out here.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawDeliveryConfig {
+    #[serde(default = "default_max_attempts")]
+    max_attempts : u32,
+    #[serde(default = "default_initial_backoff")]
+    initial_backoff : u64,
+    #[serde(default = "default_max_backoff")]
+    max_backoff : u64,
+    #[serde(default)]
+    outbox : Option<PathBuf>,
+    #[serde(default = "default_timeout")]
+    timeout : u64,
+    #[serde(default = "default_shutdown_timeout")]
+    shutdown_timeout : u64
+}
+
+impl From<RawDeliveryConfig> for DeliveryConfig {
+    fn from(raw : RawDeliveryConfig) -> Self {
+        Self {
+            retry: RetryConfig {
+                max_attempts: raw.max_attempts,
+                initial_backoff: raw.initial_backoff,
+                max_backoff: raw.max_backoff
+            },
+            outbox: raw.outbox,
+            timeout: raw.timeout,
+            shutdown_timeout: raw.shutdown_timeout
+        }
+    }
+}
+
+fn default_timeout() -> u64 { 30 }
+
+fn default_shutdown_timeout() -> u64 { 10 }
+
+/// Retry policy, set globally in `delivery` or per provider instance in `retry`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts : u32,
+    /// Seconds to wait before the first retry, doubled on every following attempt
+    #[serde(default = "default_initial_backoff")]
+    pub initial_backoff : u64,
+    /// Upper bound in seconds of the backoff
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff : u64
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            initial_backoff: default_initial_backoff(),
+            max_backoff: default_max_backoff()
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn backoff(&self, attempts : u32) -> Duration {
+        let exponent = attempts.saturating_sub(1).min(31);
+        let secs = self.initial_backoff.saturating_mul(1u64 << exponent).min(self.max_backoff);
+        Duration::from_secs(secs)
+    }
+}
+
+fn default_max_attempts() -> u32 { 5 }
+
+fn default_initial_backoff() -> u64 { 2 }
+
+fn default_max_backoff() -> u64 { 300 }
+
+/// Limits a provider instance to `max` notifications per `period` seconds. Notifications over
+/// the limit are held back until the provider is below it again.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub max : usize,
+    #[serde(default = "default_rate_limit_period")]
+    pub period : u64
+}
+
+fn default_rate_limit_period() -> u64 { 60 }
+
+/// Groups the notifications a provider instance receives within `window` seconds into one.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BatchConfig {
+    pub window : u64
+}
+
+struct RateLimiter {
+    conf : RateLimitConfig,
+    sent : VecDeque<Instant>
+}
+
+impl RateLimiter {
+    /// Returns when the next notification may be sent, if it can't be sent right away.
+    fn delay_until(&mut self, now : Instant) -> Option<Instant> {
+        let period = Duration::from_secs(self.conf.period);
+        while self.sent.front().is_some_and(|t| now.saturating_duration_since(*t) >= period) {
+            self.sent.pop_front();
+        }
+
+        if self.sent.len() < self.conf.max {
+            return None;
+        }
+
+        self.sent.front().map(|t| *t + period)
+    }
+}
+
+/// A notification waiting to be sent to a single provider instance.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PendingDelivery {
+    pub provider : String,
+    pub data : NotificationData,
+    #[serde(default)]
+    pub attempts : u32,
+    /// The event filters of the provider have already been applied, e.g. to the notifications
+    /// a summary of deferred notifications is made of
+    #[serde(default)]
+    pub filtered : bool
+}
+
+/// Messages handed to the delivery task.
+enum Command {
+    Deliver(PendingDelivery),
+    /// Replaces the providers and delivery settings, keeping pending notifications
+    Reload(Box<Config>, ProviderRegistry)
+}
+
+/// Queue handing notifications to a delivery task, which sends them concurrently and retries
+/// failures.
+pub struct DeliveryQueue {
+    sender : Option<UnboundedSender<Command>>,
+    worker : Option<Worker>,
+    handle : Option<JoinHandle<()>>
+}
+
+impl DeliveryQueue {
+    /// Creates the queue. Notifications are buffered until `spawn` starts the delivery task.
+    pub fn new(conf : &Config, registry : &ProviderRegistry, metrics : Metrics) -> Self {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let worker = Worker::new(conf, registry, metrics, receiver);
+
+        Self { sender: Some(sender), worker: Some(worker), handle: None }
+    }
+
+    /// Starts the delivery task on the current tokio runtime, if it isn't running already.
+    pub fn spawn(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            self.handle = Some(tokio::spawn(worker.run()));
+        }
+    }
+
+    pub fn enqueue(&self, provider : &str, data : NotificationData) -> Result<()> {
+        self.send(PendingDelivery { provider: provider.to_owned(), data, attempts: 0, filtered: false })
+    }
+
+    /// Enqueues a notification that has already been checked against the event filters of the
+    /// provider, such as a summary of deferred notifications whose event is `digest`.
+    pub fn enqueue_filtered(&self, provider : &str, data : NotificationData) -> Result<()> {
+        self.send(PendingDelivery { provider: provider.to_owned(), data, attempts: 0, filtered: true })
+    }
+
+    fn send(&self, delivery : PendingDelivery) -> Result<()> {
+        let sender = self.sender.as_ref().ok_or_else(|| Error::Message("delivery queue has been shut down".to_owned()))?;
+        sender.send(Command::Deliver(delivery))
+            .map_err(|_| Error::Message("delivery worker has stopped".to_owned()))
+    }
+
+    /// Switches to the providers of `registry` and the delivery settings of `conf`.
+    /// Notifications already queued are kept, and are dropped once due if their provider is gone.
+    pub fn reload(&mut self, conf : &Config, registry : &ProviderRegistry) -> Result<()> {
+        if let Some(worker) = &mut self.worker {
+            worker.apply(conf, registry);
+            return Ok(());
+        }
+
+        let sender = self.sender.as_ref().ok_or_else(|| Error::Message("delivery queue has been shut down".to_owned()))?;
+        sender.send(Command::Reload(Box::new(conf.clone()), registry.clone()))
+            .map_err(|_| Error::Message("delivery worker has stopped".to_owned()))
+    }
+
+    /// Stops accepting notifications and makes one last attempt at sending the pending ones,
+    /// waiting at most `deadline` for them. Whatever couldn't be sent is left in the outbox.
+    pub async fn shutdown(&mut self, deadline : Duration) {
+        self.spawn();
+        self.sender.take();
+
+        if let Some(mut handle) = self.handle.take() {
+            if tokio::time::timeout(deadline, &mut handle).await.is_err() {
+                warn!("Pending notifications weren't sent within {}s, giving up on them", deadline.as_secs());
+                handle.abort();
+            }
+        }
+    }
+}
+
+struct Scheduled {
+    due : Instant,
+    delivery : PendingDelivery
+}
+
+struct Batch {
+    due : Instant,
+    items : Vec<NotificationData>
+}
+
+/// Outcome of a single attempt, reported back by the task that made it.
+struct Attempt {
+    id : u64,
+    delivery : PendingDelivery,
+    result : std::result::Result<(), ProviderError>
+}
+
+struct Worker {
+    providers : HashMap<String, Provider>,
+    severity : HashMap<Event, Severity>,
+    retry : HashMap<String, RetryConfig>,
+    rate_limits : HashMap<String, RateLimiter>,
+    batch_windows : HashMap<String, Duration>,
+    batches : HashMap<String, Batch>,
+    default_retry : RetryConfig,
+    timeout : Duration,
+    outbox : Option<PathBuf>,
+    pending : Vec<Scheduled>,
+    in_flight : HashMap<u64, PendingDelivery>,
+    next_id : u64,
+    metrics : Metrics,
+    receiver : UnboundedReceiver<Command>
+}
+
+impl Worker {
+    fn new(conf : &Config, registry : &ProviderRegistry, metrics : Metrics, receiver : UnboundedReceiver<Command>) -> Self {
+        let mut worker = Self {
+            providers: HashMap::new(),
+            severity: HashMap::new(),
+            retry: HashMap::new(),
+            rate_limits: HashMap::new(),
+            batch_windows: HashMap::new(),
+            batches: HashMap::new(),
+            default_retry: conf.delivery.retry.clone(),
+            timeout: Duration::from_secs(conf.delivery.timeout),
+            outbox: conf.delivery.outbox.clone(),
+            pending: Vec::new(),
+            in_flight: HashMap::new(),
+            next_id: 0,
+            metrics,
+            receiver
+        };
+        worker.apply(conf, registry);
+
+        if let Some(path) = &worker.outbox {
+            match load_outbox(path) {
+                Ok(deliveries) => {
+                    if !deliveries.is_empty() {
+                        debug!("Loaded {} pending notifications from outbox", deliveries.len());
+                    }
+                    let now = Instant::now();
+                    worker.pending.extend(deliveries.into_iter().map(|delivery| Scheduled { due: now, delivery }));
+                },
+                Err(err) => error!("Unable to load outbox {}: {}", path.display(), err)
+            }
+        }
+
+        worker
+    }
+
+    /// Takes the providers and delivery settings from `conf` and `registry`. Rate limiters of
+    /// providers that are kept remember what they have sent.
+    fn apply(&mut self, conf : &Config, registry : &ProviderRegistry) {
+        let mut rate_limits = std::mem::take(&mut self.rate_limits);
+        self.providers.clear();
+        self.retry.clear();
+        self.batch_windows.clear();
+
+        for provider in registry.providers() {
+            let name = &provider.name;
+            if let Some(val) = &provider.settings.retry {
+                self.retry.insert(name.clone(), val.clone());
+            }
+            if let Some(val) = &provider.settings.rate_limit {
+                let sent = rate_limits.remove(name).map(|limiter| limiter.sent).unwrap_or_default();
+                self.rate_limits.insert(name.clone(), RateLimiter { conf: val.clone(), sent });
+            }
+            if let Some(val) = &provider.settings.batch {
+                self.batch_windows.insert(name.clone(), Duration::from_secs(val.window));
+            }
+            self.providers.insert(name.clone(), provider.clone());
+        }
+
+        self.severity = conf.severity.clone();
+        self.default_retry = conf.delivery.retry.clone();
+        self.timeout = Duration::from_secs(conf.delivery.timeout);
+        self.outbox = conf.delivery.outbox.clone();
+    }
+
+    async fn run(mut self) {
+        let (results_tx, mut results_rx) = tokio::sync::mpsc::unbounded_channel::<Attempt>();
+        self.process_due(&results_tx);
+
+        loop {
+            let next_due = self.pending.iter().map(|s| s.due)
+                .chain(self.batches.values().map(|b| b.due))
+                .min();
+
+            tokio::select! {
+                received = self.receiver.recv() => match received {
+                    Some(Command::Deliver(delivery)) => {
+                        self.receive(delivery);
+                        self.persist();
+                    },
+                    Some(Command::Reload(conf, registry)) => {
+                        self.apply(&conf, &registry);
+                        self.persist();
+                    },
+                    None => {
+                        debug!("Delivery queue closed, flushing pending notifications");
+                        self.drain(&results_tx, &mut results_rx).await;
+                        return;
+                    }
+                },
+                Some(attempt) = results_rx.recv() => self.complete(attempt),
+                _ = tokio::time::sleep_until(next_due.unwrap_or_else(Instant::now)), if next_due.is_some() => {}
+            }
+
+            self.process_due(&results_tx);
+        }
+    }
+
+    /// Attempts every pending notification once more, ignoring backoff and batch windows, and
+    /// waits for the attempts to finish.
+    async fn drain(&mut self, results_tx : &UnboundedSender<Attempt>, results_rx : &mut UnboundedReceiver<Attempt>) {
+        let now = Instant::now();
+        for batch in self.batches.values_mut() {
+            batch.due = now;
+        }
+        for scheduled in &mut self.pending {
+            scheduled.due = now;
+        }
+        self.process_due(results_tx);
+
+        // Failures aren't retried anymore, they stay pending and end up in the outbox
+        while !self.in_flight.is_empty() {
+            match results_rx.recv().await {
+                Some(attempt) => self.complete(attempt),
+                None => break
+            }
+        }
+
+        if !self.pending.is_empty() {
+            warn!("{} notifications couldn't be sent before shutting down", self.pending.len());
+        }
+        self.persist();
+    }
+
+    fn receive(&mut self, mut delivery : PendingDelivery) {
+        let now = Instant::now();
+
+        if let Some(provider) = self.providers.get(&delivery.provider) {
+            if !delivery.filtered && !provider.accepts(&delivery.data.event) {
+                debug!("Provider {} doesn't accept {:?} events", delivery.provider, delivery.data.event);
+                return;
+            }
+            delivery.data.severity = delivery.data.severity
+                .or_else(|| provider.severity(&delivery.data.event))
+                .or_else(|| self.severity.get(&delivery.data.event).copied())
+                .or_else(|| delivery.data.event.default_severity());
+        }
+
+        match self.batch_windows.get(&delivery.provider) {
+            Some(window) => {
+                let batch = self.batches.entry(delivery.provider.clone()).or_insert_with(|| Batch { due: now + *window, items: Vec::new() });
+                batch.items.push(delivery.data);
+            },
+            None => self.pending.push(Scheduled { due: now, delivery })
+        }
+    }
+
+    fn process_due(&mut self, results : &UnboundedSender<Attempt>) {
+        let now = Instant::now();
+
+        let due_batches : Vec<String> = self.batches.iter().filter(|(_, b)| b.due <= now).map(|(k, _)| k.clone()).collect();
+        for provider in due_batches {
+            if let Some(batch) = self.batches.remove(&provider) {
+                debug!("Sending batch of {} notifications via {}", batch.items.len(), provider);
+                self.pending.push(Scheduled { due: now, delivery: PendingDelivery { provider, data: merge_batch(batch.items), attempts: 0, filtered: true } });
+            }
+        }
+
+        let (due, waiting) : (Vec<Scheduled>, Vec<Scheduled>) = std::mem::take(&mut self.pending).into_iter().partition(|s| s.due <= now);
+        self.pending = waiting;
+
+        if due.is_empty() {
+            return;
+        }
+
+        for scheduled in due {
+            if let Some(rescheduled) = self.attempt(scheduled.delivery, results) {
+                self.pending.push(rescheduled);
+            }
+        }
+
+        self.persist();
+    }
+
+    /// Starts sending a delivery in its own task, returning the delivery again if it has to wait.
+    fn attempt(&mut self, mut delivery : PendingDelivery, results : &UnboundedSender<Attempt>) -> Option<Scheduled> {
+        let provider = match self.providers.get(&delivery.provider) {
+            Some(provider) => provider.clone(),
+            None => {
+                warn!("Dropping notification for unknown provider {}", delivery.provider);
+                return None;
+            }
+        };
+
+        if !provider.enabled() {
+            return None;
+        }
+
+        if let Some(limiter) = self.rate_limits.get_mut(&delivery.provider) {
+            let now = Instant::now();
+            if let Some(due) = limiter.delay_until(now) {
+                debug!("Provider {} is rate limited, holding back notification", delivery.provider);
+                return Some(Scheduled { due, delivery });
+            }
+            limiter.sent.push_back(now);
+        }
+
+        debug!("Sending notification via {} ({}) provider", delivery.provider, provider.kind);
+        delivery.attempts += 1;
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.in_flight.insert(id, delivery.clone());
+
+        let timeout = self.timeout;
+        let results = results.clone();
+        tokio::spawn(async move {
+            let result = match tokio::time::timeout(timeout, provider.send(delivery.data.clone())).await {
+                Ok(result) => result,
+                Err(_) => Err(ProviderError::Timeout(timeout))
+            };
+            let _ = results.send(Attempt { id, delivery, result });
+        });
+
+        None
+    }
+
+    fn complete(&mut self, attempt : Attempt) {
+        self.in_flight.remove(&attempt.id);
+        let delivery = attempt.delivery;
+
+        let err = match attempt.result {
+            Ok(_) => {
+                self.metrics.notification_sent(&delivery.provider);
+                self.persist();
+                return;
+            },
+            Err(err) => {
+                self.metrics.notification_failed(&delivery.provider);
+                err
+            }
+        };
+
+        let retry = self.retry.get(&delivery.provider).unwrap_or(&self.default_retry);
+        if !err.is_retryable() || delivery.attempts >= retry.max_attempts {
+            error!("Giving up on notification via {} after {} attempt(s): {}", delivery.provider, delivery.attempts, err);
+            self.persist();
+            return;
+        }
+
+        let mut delay = retry.backoff(delivery.attempts);
+        if let Some(retry_after) = err.retry_after() {
+            delay = delay.max(retry_after);
+        }
+        warn!("Notification via {} failed, retrying in {}s: {}", delivery.provider, delay.as_secs(), err);
+
+        self.pending.push(Scheduled { due: Instant::now() + delay, delivery });
+        self.persist();
+    }
+
+    fn persist(&self) {
+        if let Some(path) = &self.outbox {
+            let mut deliveries : Vec<PendingDelivery> = self.pending.iter().map(|s| s.delivery.clone()).collect();
+            deliveries.extend(self.in_flight.values().cloned());
+            for (provider, batch) in &self.batches {
+                deliveries.extend(batch.items.iter().map(|data| PendingDelivery { provider: provider.clone(), data: data.clone(), attempts: 0, filtered: true }));
+            }
+            if let Err(err) = save_outbox(path, &deliveries) {
+                error!("Unable to save outbox {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+fn load_outbox(path : &Path) -> Result<Vec<PendingDelivery>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let buf = std::fs::read(path).map_err(|e| Error::Message(e.to_string()))?;
+    serde_json::from_slice(&buf).map_err(|e| Error::Message(e.to_string()))
+}
+
+fn save_outbox(path : &Path, deliveries : &[PendingDelivery]) -> Result<()> {
+    let buf = serde_json::to_vec(deliveries).map_err(|e| Error::Message(e.to_string()))?;
+    // Write to a temporary file first, so a crash mid-write doesn't corrupt the outbox
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, buf).map_err(|e| Error::Message(e.to_string()))?;
+    std::fs::rename(&tmp_path, path).map_err(|e| Error::Message(e.to_string()))
+}
+
+/// Combines the notifications of a batch into a single one, e.g. "12 clients disconnected".
+pub fn merge_batch(mut items : Vec<NotificationData>) -> NotificationData {
+    if items.len() == 1 {
+        return items.remove(0);
+    }
+
+    let mut counts : Vec<(Event, usize)> = Vec::new();
+    for item in &items {
+        match counts.iter_mut().find(|(event, _)| *event == item.event) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((item.event.clone(), 1))
+        }
+    }
+
+    let summary : Vec<String> = counts.iter().map(|(event, count)| {
+        let plural = if *count == 1 { "" } else { "s" };
+        let clients = |what : &str| format!("{} client{} {}", count, plural, what);
+        match event {
+            Event::Connect => clients("connected"),
+            Event::Disconnect => clients("disconnected"),
+            Event::Roam => clients("roamed"),
+            Event::Flapping => clients("started flapping"),
+            Event::FlappingStopped => clients("stopped flapping"),
+            Event::QuotaWarning => clients("neared their quota"),
+            Event::QuotaExceeded => clients("exceeded their quota"),
+            Event::StalePeer => clients(if *count == 1 { "is stale" } else { "are stale" }),
+            Event::UnknownPeer => format!("{} unknown client{} connected", count, plural),
+            // Not about clients, but the daemon itself
+            Event::Digest => format!("{} digest{}", count, plural),
+            Event::Report => format!("{} report{}", count, plural),
+            Event::DaemonStopped if *count == 1 => "the daemon stopped".to_owned(),
+            Event::DaemonStopped => format!("the daemon stopped {} times", count)
+        }
+    }).collect();
+    let lines : Vec<String> = items.iter().map(|item| format!("- {}", item.msg)).collect();
+
+    NotificationData {
+        msg: format!("{}:\n{}", summary.join(", "), lines.join("\n")),
+        event: if counts.len() == 1 { counts.remove(0).0 } else { Event::Digest },
+        peer: None,
+        priority: items.iter().filter_map(|item| item.priority).max(),
+        severity: items.iter().filter_map(|item| item.severity).max()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::config::Config;
+    use crate::metrics::Metrics;
+    use crate::notifications::{Event, NotificationData, ProviderError};
+    use crate::notifications::registry::ProviderRegistry;
+    use crate::notifications::testing::{wait_for_sent, ScriptedFactory, ScriptedHandler};
+    use super::{load_outbox, merge_batch, DeliveryQueue, Instant, RateLimitConfig, RateLimiter, RetryConfig};
+
+    const PROVIDERS : &str = "
+notification_providers:
+  pager:
+    type: scripted
+    enable: true
+";
+
+    fn start(handler : &Arc<ScriptedHandler>, conf : &str, metrics : Metrics) -> DeliveryQueue {
+        let conf : Config = serde_yaml::from_str(&format!("{}{}", PROVIDERS, conf)).unwrap();
+        let mut registry = ProviderRegistry::empty();
+        registry.register(ScriptedFactory(handler.clone()));
+        registry.load(&conf).unwrap();
+
+        let mut queue = DeliveryQueue::new(&conf, &registry, metrics);
+        queue.spawn();
+        queue
+    }
+
+    fn notification(msg : &str) -> NotificationData {
+        NotificationData { msg: msg.to_owned(), event: Event::Connect, peer: None, priority: None, severity: None }
+    }
+
+    fn outbox_path(name : &str) -> PathBuf {
+        std::env::temp_dir().join(format!("wg_activity_notify_{}_{}.json", name, std::process::id()))
+    }
+
+    async fn wait_for(condition : impl Fn() -> bool) {
+        for _ in 0..300 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry() {
+        let handler = ScriptedHandler::failing(vec![ProviderError::Status(502, String::new()), ProviderError::RateLimited(None)]);
+        let queue = start(&handler, "delivery:\n  initial_backoff: 0\n", Metrics::new());
+
+        queue.enqueue("pager", notification("laptop connected")).unwrap();
+        assert_eq!(wait_for_sent(&handler, 1).await[0].msg, "laptop connected");
+        assert_eq!(handler.attempts(), 3);
+
+        // Client errors aren't retried
+        let handler = ScriptedHandler::failing(vec![ProviderError::Status(400, String::new())]);
+        let metrics = Metrics::new();
+        let queue = start(&handler, "delivery:\n  initial_backoff: 0\n", metrics.clone());
+        queue.enqueue("pager", notification("laptop connected")).unwrap();
+        wait_for(|| metrics.encode().contains("notifications_failed_total{provider=\"pager\"} 1")).await;
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(handler.attempts(), 1);
+        assert!(handler.sent().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeout() {
+        let handler = ScriptedHandler::slow(Duration::from_secs(2));
+        let metrics = Metrics::new();
+        let queue = start(&handler, "delivery:\n  max_attempts: 1\n  timeout: 1\n", metrics.clone());
+
+        queue.enqueue("pager", notification("laptop connected")).unwrap();
+        wait_for(|| metrics.encode().contains("notifications_failed_total{provider=\"pager\"} 1")).await;
+        assert!(metrics.encode().contains("notifications_failed_total{provider=\"pager\"} 1"));
+        assert_eq!(handler.attempts(), 1);
+        assert!(handler.sent().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_outbox() {
+        let path = outbox_path("outbox");
+        let conf = format!("delivery:\n  outbox: {}\n", path.display());
+
+        // Rate limited for longer than the daemon keeps running
+        let limited = || ProviderError::RateLimited(Some(Duration::from_secs(60)));
+        let handler = ScriptedHandler::failing(vec![limited(), limited()]);
+        let mut queue = start(&handler, &conf, Metrics::new());
+        queue.enqueue("pager", notification("laptop connected")).unwrap();
+        wait_for(|| handler.attempts() == 1).await;
+        queue.shutdown(Duration::from_secs(1)).await;
+
+        let outbox = load_outbox(&path).unwrap();
+        assert_eq!(outbox.len(), 1);
+        assert_eq!((outbox[0].provider.as_str(), outbox[0].data.msg.as_str(), outbox[0].attempts), ("pager", "laptop connected", 2));
+
+        // Sent once the daemon is back
+        let handler = ScriptedHandler::failing(Vec::new());
+        let mut queue = start(&handler, &conf, Metrics::new());
+        assert_eq!(wait_for_sent(&handler, 1).await[0].msg, "laptop connected");
+        queue.shutdown(Duration::from_secs(1)).await;
+        assert!(load_outbox(&path).unwrap().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown() {
+        // Batches are flushed without waiting for their window
+        let handler = ScriptedHandler::failing(Vec::new());
+        let mut queue = start(&handler, "    batch:\n      window: 60\n", Metrics::new());
+        queue.enqueue("pager", notification("laptop connected")).unwrap();
+        queue.enqueue("pager", notification("phone connected")).unwrap();
+        let started = Instant::now();
+        queue.shutdown(Duration::from_secs(5)).await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(handler.sent().len(), 1);
+        assert!(handler.sent()[0].msg.starts_with("2 clients connected:"));
+        assert!(queue.enqueue("pager", notification("laptop connected")).is_err());
+
+        // What isn't sent within the deadline is left in the outbox
+        let path = outbox_path("shutdown");
+        let handler = ScriptedHandler::slow(Duration::from_secs(5));
+        let mut queue = start(&handler, &format!("delivery:\n  outbox: {}\n", path.display()), Metrics::new());
+        queue.enqueue("pager", notification("laptop connected")).unwrap();
+        wait_for(|| handler.attempts() == 1).await;
+        let started = Instant::now();
+        queue.shutdown(Duration::from_millis(200)).await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(handler.sent().is_empty());
+        assert_eq!(load_outbox(&path).unwrap().len(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit() {
+        let mut limiter = RateLimiter { conf: RateLimitConfig { max: 2, period: 60 }, sent: Default::default() };
+        let now = Instant::now();
+        for secs in [0, 10] {
+            assert_eq!(limiter.delay_until(now + Duration::from_secs(secs)), None);
+            limiter.sent.push_back(now + Duration::from_secs(secs));
+        }
+        assert_eq!(limiter.delay_until(now + Duration::from_secs(20)), Some(now + Duration::from_secs(60)));
+        assert_eq!(limiter.delay_until(now + Duration::from_secs(60)), None);
+
+        let handler = ScriptedHandler::failing(Vec::new());
+        let queue = start(&handler, "    rate_limit:\n      max: 1\n      period: 1\n", Metrics::new());
+        queue.enqueue("pager", notification("laptop connected")).unwrap();
+        queue.enqueue("pager", notification("phone connected")).unwrap();
+        let started = Instant::now();
+        wait_for_sent(&handler, 1).await;
+        tokio::time::advance(Duration::from_millis(900)).await;
+        assert_eq!(handler.sent().len(), 1);
+
+        // Released once the period is over
+        assert_eq!(wait_for_sent(&handler, 2).await[1].msg, "phone connected");
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let retry = RetryConfig { max_attempts: 5, initial_backoff: 2, max_backoff: 10 };
+
+        assert_eq!(retry.backoff(1), Duration::from_secs(2));
+        assert_eq!(retry.backoff(2), Duration::from_secs(4));
+        assert_eq!(retry.backoff(3), Duration::from_secs(8));
+        assert_eq!(retry.backoff(4), Duration::from_secs(10));
+        assert_eq!(retry.backoff(100), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_merge_batch() {
+        let item = |msg : &str, event : Event| NotificationData { msg: msg.to_owned(), event, peer: None, priority: None, severity: None };
+
+        let merged = merge_batch(vec![item("a", Event::Disconnect), item("b", Event::Disconnect)]);
+        assert_eq!(merged.event, Event::Disconnect);
+        assert_eq!(merged.msg, "2 clients disconnected:\n- a\n- b");
+
+        let merged = merge_batch(vec![item("a", Event::Disconnect), item("b", Event::Connect)]);
+        assert_eq!(merged.event, Event::Digest);
+        assert_eq!(merged.msg, "1 client disconnected, 1 client connected:\n- a\n- b");
+
+        let merged = merge_batch(vec![item("a", Event::Disconnect), item("b", Event::DaemonStopped)]);
+        assert_eq!(merged.msg, "1 client disconnected, the daemon stopped:\n- a\n- b");
+
+        let merged = merge_batch(vec![item("a", Event::UnknownPeer), item("b", Event::StalePeer)]);
+        assert_eq!(merged.msg, "1 unknown client connected, 1 client is stale:\n- a\n- b");
+    }
+}
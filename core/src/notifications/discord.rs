@@ -1,44 +1,47 @@
-use crate::notifications::{Event, NotificationHandler, NotificationData, Provider};
+use std::sync::Arc;
+use async_trait::async_trait;
+use crate::notifications::{check_response, Event, NotificationHandler, NotificationData};
+use crate::notifications::registry::ProviderFactory;
 use serde::{Serialize, Deserialize};
-use crate::{Config, ConfigError, ProviderError};
-use crate::error::Error;
+use crate::{ConfigError, ProviderError};
+use crate::secrets::Secret;
 
-pub struct Discord {}
+pub struct Discord {
+    conf : DiscordConfig,
+    cli : reqwest::Client
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct DiscordConfig {
-    webhook_url : String,
-    enable: bool
+    webhook_url : Secret
 }
 
-pub fn new() -> Result<Provider, ConfigError> {
-    Ok(Provider {
-        name: "Discord".to_string(),
-        description: "".to_string(),
-        config: Config::get_notification_provider_config("discord")?,
-        handler: Some(Box::new(Discord {}))
-    })
-}
+pub struct DiscordFactory;
+
+impl ProviderFactory for DiscordFactory {
+    fn kind(&self) -> &str {
+        "discord"
+    }
 
-impl Discord {
-    pub fn load_config() -> Result<DiscordConfig, ConfigError> {
-        Config::get_notification_provider_config("discord")
+    fn build(&self, _instance : &str, config : &serde_yaml::Value) -> Result<Arc<dyn NotificationHandler>, ConfigError> {
+        Ok(Arc::new(Discord {
+            conf: serde_yaml::from_value(config.clone())?,
+            cli: reqwest::Client::new()
+        }))
     }
 }
 
+#[async_trait]
 impl NotificationHandler for Discord {
-    fn send(&self, data : NotificationData) -> Result<(), ProviderError> {
-        let conf = Discord::load_config()?;
-        let cli = reqwest::blocking::Client::new();
-        let color = match data.event {
-            Event::Connect => 6680723,
-            Event::Disconnect => 14708848
+    async fn send(&self, data : NotificationData) -> Result<(), ProviderError> {
+        let conf = &self.conf;
+        let color = match data.severity {
+            Some(severity) => severity.discord_color(),
+            None => event_color(&data.event)
         };
 
-        let title = match data.event {
-            Event::Connect => "New client connection",
-            Event::Disconnect => "Client disconnected"
-        };
+        let title = data.event.title();
 
         let payload = DiscordPayload {
             content: "".to_owned(),
@@ -54,14 +57,30 @@ impl NotificationHandler for Discord {
             ]
         };
 
-        cli.post(conf.webhook_url)
+        let resp = self.cli.post(conf.webhook_url.expose())
             .json(&payload)
             .send()
-            .map_or_else(|e| { Err(ProviderError::ReqwestErr(e)) }, |_| { Ok(())})
+            .await
+            .map_err(ProviderError::ReqwestErr)?;
+
+        check_response(resp).await
     }
+}
 
-    fn get_provider(&self) -> Result<Provider, ConfigError> {
-        new()
+fn event_color(event : &Event) -> i64 {
+    match event {
+        Event::Connect => 6680723,
+        Event::Disconnect => 14708848,
+        Event::Roam => 10181046,
+        Event::Flapping => 16098851,
+        Event::FlappingStopped => 9807270,
+        Event::Digest => 3447003,
+        Event::DaemonStopped => 9807270,
+        Event::QuotaWarning => 16098851,
+        Event::QuotaExceeded => 14708848,
+        Event::Report => 3447003,
+        Event::StalePeer => 9807270,
+        Event::UnknownPeer => 14708848
     }
 }
 
@@ -1,102 +1,313 @@
 use std::collections::HashMap;
-use reqwest::blocking::Response;
-use reqwest::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use reqwest::StatusCode;
 use serde::{Serialize, Deserialize};
 use crate::ConfigError;
+use crate::notifications::delivery::{BatchConfig, RateLimitConfig, RetryConfig};
+use crate::quiet_hours::QuietHours;
 use thiserror::Error;
 
+pub mod delivery;
 pub mod discord;
 pub mod pushover;
+pub mod registry;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NotificationData {
     pub msg : String,
-    pub event : Event
+    pub event : Event,
+    pub peer : Option<PeerInfo>,
+    /// Priority override set by the matching route
+    #[serde(default)]
+    pub priority : Option<i32>,
+    /// Severity of the event for the provider it's sent to
+    #[serde(default)]
+    pub severity : Option<Severity>
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
 pub enum Event {
     Connect,
-    Disconnect
+    Disconnect,
+    /// A connected peer changed endpoint
+    Roam,
+    Flapping,
+    FlappingStopped,
+    /// Summary of several events
+    Digest,
+    DaemonStopped,
+    /// A peer used the warning percentage of its traffic quota
+    QuotaWarning,
+    /// A peer used up its traffic quota
+    QuotaExceeded,
+    /// Scheduled status report
+    Report,
+    /// A peer never connected, or hasn't in a long time
+    StalePeer,
+    /// A peer without a friendly name or inventory entry connected
+    UnknownPeer
 }
 
-pub trait NotificationHandler {
-    fn send(&self, data : NotificationData) -> Result<(), ProviderError>;
-    fn get_provider(&self) -> Result<Provider, ConfigError>;
+impl Event {
+    /// Name of the event as written in the config, e.g. `flapping_stopped`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Event::Connect => "connect",
+            Event::Disconnect => "disconnect",
+            Event::Roam => "roam",
+            Event::Flapping => "flapping",
+            Event::FlappingStopped => "flapping_stopped",
+            Event::Digest => "digest",
+            Event::DaemonStopped => "daemon_stopped",
+            Event::QuotaWarning => "quota_warning",
+            Event::QuotaExceeded => "quota_exceeded",
+            Event::Report => "report",
+            Event::StalePeer => "stale_peer",
+            Event::UnknownPeer => "unknown_peer"
+        }
+    }
+
+    /// Title of the notifications of the event.
+    pub fn title(&self) -> &'static str {
+        match self {
+            Event::Connect => "New client connection",
+            Event::Disconnect => "Client disconnected",
+            Event::Roam => "Client roamed",
+            Event::Flapping => "Client is flapping",
+            Event::FlappingStopped => "Client stopped flapping",
+            Event::Digest => "Activity summary",
+            Event::DaemonStopped => "wg_activity_notify stopped",
+            Event::QuotaWarning => "Client nearing its quota",
+            Event::QuotaExceeded => "Client exceeded its quota",
+            Event::Report => "Status report",
+            Event::StalePeer => "Stale client",
+            Event::UnknownPeer => "Unknown client connected"
+        }
+    }
+
+    /// Severity of the event when neither the config nor the provider set one.
+    pub fn default_severity(&self) -> Option<Severity> {
+        match self {
+            Event::UnknownPeer => Some(Severity::High),
+            _ => None
+        }
+    }
 }
 
-pub fn init_providers() -> Result<Vec<Provider>, ConfigError> {
-    let mut providers = Vec::new();
+/// How urgent an event is. Providers translate it into their own notion of priority.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Lowest,
+    Low,
+    Normal,
+    High,
+    Emergency
+}
+
+impl Severity {
+    pub fn pushover_priority(&self) -> i32 {
+        match self {
+            Severity::Lowest => -2,
+            Severity::Low => -1,
+            Severity::Normal => 0,
+            Severity::High => 1,
+            Severity::Emergency => 2
+        }
+    }
 
-    providers.push(discord::new()?);
-    providers.push(pushover::new()?);
+    pub fn discord_color(&self) -> i64 {
+        match self {
+            Severity::Lowest => 9807270,
+            Severity::Low => 6680723,
+            Severity::Normal => 3447003,
+            Severity::High => 16098851,
+            Severity::Emergency => 14708848
+        }
+    }
+}
 
-    Ok(providers)
+/// The peer an event is about.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub public_key : String,
+    pub interface : String,
+    pub endpoint : Option<String>
 }
 
-pub fn init_providers_map() -> Result<HashMap<String, Provider>, ConfigError> {
-    let mut payload = HashMap::new();
-    for provider in init_providers()? {
-        payload.insert(provider.name.to_lowercase(), provider);
-    };
+#[async_trait]
+pub trait NotificationHandler : Send + Sync {
+    async fn send(&self, data : NotificationData) -> Result<(), ProviderError>;
+}
 
-    Ok(payload)
+/// Settings shared by every provider instance, regardless of its type.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ProviderSettings {
+    /// Provider type, the name of the instance if left out
+    #[serde(default, rename = "type")]
+    pub kind : Option<String>,
+    #[serde(default)]
+    pub enable : bool,
+    /// Events the provider receives, every event if left out
+    #[serde(default)]
+    pub events : Option<Vec<Event>>,
+    #[serde(default)]
+    pub exclude_events : Vec<Event>,
+    #[serde(default)]
+    pub severity : HashMap<Event, Severity>,
+    #[serde(default)]
+    pub retry : Option<RetryConfig>,
+    #[serde(default)]
+    pub rate_limit : Option<RateLimitConfig>,
+    #[serde(default)]
+    pub batch : Option<BatchConfig>,
+    #[serde(default)]
+    pub quiet_hours : Vec<QuietHours>
 }
 
-pub fn does_provider_exist(val : &str) -> bool {
-    init_providers_map().unwrap().contains_key(val)
+impl ProviderSettings {
+    /// Keys of a provider instance that belong to the settings rather than to its type.
+    pub const FIELDS : &'static [&'static str] = &[
+        "type", "enable", "events", "exclude_events", "severity", "retry", "rate_limit", "batch", "quiet_hours"
+    ];
+
+    /// Splits the config of a provider instance into its settings and the config of its type.
+    pub fn split(config : &serde_yaml::Value) -> (serde_yaml::Value, serde_yaml::Value) {
+        let mut settings = serde_yaml::Mapping::new();
+        let mut own = serde_yaml::Mapping::new();
+
+        if let Some(mapping) = config.as_mapping() {
+            for (key, val) in mapping {
+                match key.as_str() {
+                    Some(name) if Self::FIELDS.contains(&name) => settings.insert(key.clone(), val.clone()),
+                    _ => own.insert(key.clone(), val.clone())
+                };
+            }
+        }
+
+        (serde_yaml::Value::Mapping(settings), serde_yaml::Value::Mapping(own))
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Clone)]
 pub struct Provider {
+    /// Name of the instance, i.e. its key in `notification_providers`
     pub name : String,
+    /// Provider type, e.g. `discord`
+    pub kind : String,
     #[serde(skip)]
     pub description : String,
     #[serde(skip)]
-    pub config : std::collections::HashMap<String, serde_yaml::Value>,
+    pub settings : ProviderSettings,
     #[serde(skip)]
-    pub handler : Option<Box<dyn NotificationHandler>>
-}
-
-impl Clone for Provider {
-    fn clone(&self) -> Self {
-        self.handler.as_ref().expect("No handler was provided for Provider").get_provider().unwrap()
-    }
+    pub handler : Arc<dyn NotificationHandler>
 }
 
 impl Provider {
-    pub fn send(&self, data : NotificationData) -> Result<(), ProviderError> {
-        return match self.handler.as_ref() {
-            Some(handler) => {
-                handler.send(data)
-            },
-            None => Ok(())
-        }
+    pub async fn send(&self, data : NotificationData) -> Result<(), ProviderError> {
+        self.handler.send(data).await
     }
 
     pub fn enabled(&self) -> bool {
-        return match self.config.get("enable") {
-            Some(val) => {
-                return match val.as_bool() {
-                    Some(enable) => enable,
-                    None => false
-                }
-            },
-            None => false
+        self.settings.enable
+    }
+
+    /// Whether the provider wants to receive the event, according to its `events` allowlist
+    /// and `exclude_events` denylist.
+    pub fn accepts(&self, event : &Event) -> bool {
+        if let Some(events) = &self.settings.events {
+            if !events.contains(event) {
+                return false;
+            }
         }
+
+        !self.settings.exclude_events.contains(event)
+    }
+
+    /// Severity of the event according to the `severity` mapping of the provider.
+    pub fn severity(&self, event : &Event) -> Option<Severity> {
+        self.settings.severity.get(event).copied()
     }
 }
 
 #[derive(Error, Debug)]
 pub enum ProviderError {
     #[error("config error: {0:?}")]
-    CustomError(Box<dyn std::error::Error>),
+    CustomError(Box<dyn std::error::Error + Send + Sync>),
     #[error("error: {0}")]
     Message(String),
     #[error("config error: {0:?}")]
     ConfigErr(ConfigError),
     #[error("reqwest error: {0:?}")]
     ReqwestErr(reqwest::Error),
+    #[error("rate limited, retry after {0:?}")]
+    RateLimited(Option<Duration>),
+    #[error("unexpected response status {0}: {1}")]
+    Status(u16, String),
+    #[error("timed out after {0:?}")]
+    Timeout(Duration),
+}
+
+impl ProviderError {
+    /// Whether sending the notification again later might succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ProviderError::ReqwestErr(_) => true,
+            ProviderError::RateLimited(_) => true,
+            ProviderError::Timeout(_) => true,
+            ProviderError::Status(status, _) => *status >= 500,
+            _ => false
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ProviderError::RateLimited(retry_after) => *retry_after,
+            _ => None
+        }
+    }
+}
+
+/// Turns an unsuccessful response into a `ProviderError`, picking up the delay a rate limited
+/// response asks for from either the Retry-After header, in seconds or as an HTTP date, or
+/// Discord's `retry_after` body field.
+pub async fn check_response(resp : reqwest::Response) -> Result<(), ProviderError> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(());
+    }
+
+    let header_retry_after = resp.headers().get(reqwest::header::RETRY_AFTER)
+        .and_then(|val| val.to_str().ok())
+        .and_then(parse_retry_after);
+    let body = resp.text().await.unwrap_or_default();
+
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        let body_retry_after = serde_json::from_str::<serde_json::Value>(&body).ok()
+            .and_then(|val| val.get("retry_after").and_then(|v| v.as_f64()));
+        let retry_after = header_retry_after.or(body_retry_after)
+            .filter(|secs| secs.is_finite() && *secs >= 0.0)
+            .map(Duration::from_secs_f64);
+        return Err(ProviderError::RateLimited(retry_after));
+    }
+
+    Err(ProviderError::Status(status.as_u16(), body))
+}
+
+/// Parses a Retry-After value into seconds from now.
+fn parse_retry_after(val : &str) -> Option<f64> {
+    let val = val.trim();
+    if let Ok(secs) = val.parse::<f64>() {
+        return Some(secs);
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(val).ok()?;
+    let millis = date.signed_duration_since(chrono::Utc::now()).num_milliseconds();
+    Some(millis.max(0) as f64 / 1000.0)
 }
 
 impl From<ConfigError> for ProviderError {
@@ -109,4 +320,119 @@ impl From<reqwest::Error> for ProviderError {
     fn from(e: reqwest::Error) -> Self {
         Self::ReqwestErr(e)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use async_trait::async_trait;
+    use crate::config::ConfigError;
+    use super::{NotificationData, NotificationHandler, ProviderError};
+    use super::registry::ProviderFactory;
+
+    /// Handler failing with scripted errors before it succeeds, recording what it sent.
+    #[derive(Default)]
+    pub struct ScriptedHandler {
+        failures : Mutex<VecDeque<ProviderError>>,
+        delay : Option<Duration>,
+        attempts : Mutex<usize>,
+        sent : Mutex<Vec<NotificationData>>
+    }
+
+    impl ScriptedHandler {
+        pub fn failing(failures : Vec<ProviderError>) -> Arc<Self> {
+            Arc::new(Self { failures: Mutex::new(failures.into()), ..Self::default() })
+        }
+
+        /// A handler taking `delay` to send every notification.
+        pub fn slow(delay : Duration) -> Arc<Self> {
+            Arc::new(Self { delay: Some(delay), ..Self::default() })
+        }
+
+        pub fn attempts(&self) -> usize {
+            *self.attempts.lock().unwrap()
+        }
+
+        pub fn sent(&self) -> Vec<NotificationData> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    /// Waits for `handler` to have sent `n` notifications, in the background, and returns them.
+    pub async fn wait_for_sent(handler : &ScriptedHandler, n : usize) -> Vec<NotificationData> {
+        for _ in 0..100 {
+            if handler.sent().len() >= n {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        handler.sent()
+    }
+
+    #[async_trait]
+    impl NotificationHandler for ScriptedHandler {
+        async fn send(&self, data : NotificationData) -> Result<(), ProviderError> {
+            *self.attempts.lock().unwrap() += 1;
+            if let Some(delay) = self.delay {
+                tokio::time::sleep(delay).await;
+            }
+            if let Some(err) = self.failures.lock().unwrap().pop_front() {
+                return Err(err);
+            }
+            self.sent.lock().unwrap().push(data);
+            Ok(())
+        }
+    }
+
+    /// Factory of the `scripted` provider type, handing the same handler to every instance.
+    pub struct ScriptedFactory(pub Arc<ScriptedHandler>);
+
+    impl ProviderFactory for ScriptedFactory {
+        fn kind(&self) -> &str {
+            "scripted"
+        }
+
+        fn build(&self, _instance : &str, _config : &serde_yaml::Value) -> Result<Arc<dyn NotificationHandler>, ConfigError> {
+            Ok(self.0.clone())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{check_response, ProviderError};
+
+    fn response(status : u16, retry_after : Option<&str>, body : &'static str) -> reqwest::Response {
+        let mut builder = axum::http::Response::builder().status(status);
+        if let Some(val) = retry_after {
+            builder = builder.header("retry-after", val);
+        }
+        reqwest::Response::from(builder.body(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_check_response() {
+        assert!(check_response(response(204, None, "")).await.is_ok());
+
+        let err = check_response(response(429, Some("3"), "")).await.unwrap_err();
+        assert_eq!(err.retry_after(), Some(Duration::from_secs(3)));
+
+        // Discord tells how long to wait in the body
+        let err = check_response(response(429, None, r#"{"message": "You are being rate limited.", "retry_after": 1.5}"#)).await.unwrap_err();
+        assert_eq!(err.retry_after(), Some(Duration::from_millis(1500)));
+
+        let date = (chrono::Utc::now() + chrono::Duration::seconds(120)).format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        let retry_after = check_response(response(429, Some(&date), "")).await.unwrap_err().retry_after().unwrap();
+        assert!(retry_after > Duration::from_secs(110) && retry_after <= Duration::from_secs(120));
+
+        let err = check_response(response(429, Some("Wed, 21 Oct 2015 07:28:00 GMT"), "")).await.unwrap_err();
+        assert_eq!(err.retry_after(), Some(Duration::ZERO));
+
+        match check_response(response(503, None, "down")).await {
+            Err(ProviderError::Status(503, body)) => assert_eq!(body, "down"),
+            other => panic!("unexpected result {:?}", other)
+        }
+    }
+}
@@ -1,64 +1,83 @@
-use crate::notifications::{Event, NotificationHandler, NotificationData, Provider};
+use std::sync::Arc;
+use async_trait::async_trait;
+use crate::notifications::{check_response, NotificationHandler, NotificationData, Severity};
+use crate::notifications::registry::ProviderFactory;
 use serde::{Serialize, Deserialize};
-use crate::{Config, ConfigError, ProviderError};
-use crate::error::Error;
+use crate::{ConfigError, ProviderError};
+use crate::secrets::Secret;
 
-pub struct Pushover {}
+pub struct Pushover {
+    conf : PushoverConfig,
+    cli : reqwest::Client
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct PushoverConfig {
-    api_key : String,
-    device_key : String,
+    api_key : Secret,
+    device_key : Secret,
     #[serde(default = "default_priority")]
-    priority: i32,
-    enable: bool
+    priority: i32
 }
 
 fn default_priority() -> i32 {
     1
 }
 
-pub fn new() -> Result<Provider, ConfigError> {
-    Ok(Provider {
-        name: "Pushover".to_string(),
-        description: "".to_string(),
-        config: Config::get_notification_provider_config("pushover")?,
-        handler: Some(Box::new(Pushover {}))
-    })
+/// Priority of a notification: the one it was routed with, the one of its severity, or the
+/// one of the instance. A high severity is made to stand out from the instance's priority,
+/// which is already high by default.
+fn priority(conf : &PushoverConfig, data : &NotificationData) -> i32 {
+    let from_severity = data.severity.map(|severity| match severity {
+        Severity::High => severity.pushover_priority().max(conf.priority + 1).min(2),
+        severity => severity.pushover_priority()
+    });
+    data.priority.or(from_severity).unwrap_or(conf.priority)
 }
 
-impl Pushover {
-    pub fn load_config() -> Result<PushoverConfig, ConfigError> {
-        Config::get_notification_provider_config("pushover")
+pub struct PushoverFactory;
+
+impl ProviderFactory for PushoverFactory {
+    fn kind(&self) -> &str {
+        "pushover"
+    }
+
+    fn build(&self, _instance : &str, config : &serde_yaml::Value) -> Result<Arc<dyn NotificationHandler>, ConfigError> {
+        Ok(Arc::new(Pushover {
+            conf: serde_yaml::from_value(config.clone())?,
+            cli: reqwest::Client::new()
+        }))
     }
 }
 
+#[async_trait]
 impl NotificationHandler for Pushover {
-    fn send(&self, data : NotificationData) -> Result<(), ProviderError> {
-        let conf = Pushover::load_config()?;
-        let cli = reqwest::blocking::Client::new();
+    async fn send(&self, data : NotificationData) -> Result<(), ProviderError> {
+        let conf = &self.conf;
 
-        let title = match data.event {
-            Event::Connect => "New client connection",
-            Event::Disconnect => "Client disconnected"
-        };
+        let title = data.event.title();
+
+        let priority = priority(conf, &data);
+        // Emergency priority notifications are repeated until acknowledged, which requires these
+        let (retry, expire) = if priority >= 2 { (Some(60), Some(3600)) } else { (None, None) };
 
         let payload = PushoverPayload {
-            token: conf.api_key.clone(),
-            user: conf.device_key.clone(),
+            token: conf.api_key.expose().to_owned(),
+            user: conf.device_key.expose().to_owned(),
             title: title.to_owned(),
             message: data.msg.clone(),
-            priority: conf.priority
+            priority,
+            retry,
+            expire
         };
 
-        cli.post("https://api.pushover.net/1/messages.json")
+        let resp = self.cli.post("https://api.pushover.net/1/messages.json")
             .json(&payload)
             .send()
-            .map_or_else(|e| { Err(ProviderError::ReqwestErr(e)) }, |_| { Ok(())})
-    }
+            .await
+            .map_err(ProviderError::ReqwestErr)?;
 
-    fn get_provider(&self) -> Result<Provider, ConfigError> {
-        new()
+        check_response(resp).await
     }
 }
 
@@ -69,4 +88,30 @@ pub struct PushoverPayload {
     pub title: String,
     pub message: String,
     pub priority: i32,
-}
\ No newline at end of file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expire: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::notifications::{Event, NotificationData, Severity};
+    use super::{priority, PushoverConfig};
+
+    #[test]
+    fn test_priority() {
+        let conf : PushoverConfig = serde_yaml::from_str("api_key: a\ndevice_key: b\n").unwrap();
+        let data = |event : Event, severity : Option<Severity>| NotificationData { msg: String::new(), event, peer: None, priority: None, severity };
+
+        assert_eq!(priority(&conf, &data(Event::Connect, None)), 1);
+        assert_eq!(priority(&conf, &data(Event::Connect, Some(Severity::Low))), -1);
+        // Unknown peers stand out from the default priority
+        let unknown = Event::UnknownPeer.default_severity();
+        assert_eq!(priority(&conf, &data(Event::UnknownPeer, unknown)), 2);
+
+        let conf = PushoverConfig { priority: 0, ..conf };
+        assert_eq!(priority(&conf, &data(Event::UnknownPeer, unknown)), 1);
+        assert_eq!(priority(&conf, &NotificationData { priority: Some(-2), ..data(Event::UnknownPeer, unknown) }), -2);
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::error;
+use crate::config::{Config, ConfigError};
+use crate::notifications::{discord, pushover, NotificationHandler, Provider, ProviderSettings};
+
+/// Builds the handler of a provider type from the config of an instance.
+///
+/// Implement this to add a provider type of your own and register it with
+/// `ProviderRegistry::register`.
+pub trait ProviderFactory : Send + Sync {
+    /// Provider type, matched against the `type` field of provider instances
+    fn kind(&self) -> &str;
+    fn description(&self) -> &str {
+        ""
+    }
+    /// Builds the handler of an instance. `config` doesn't contain the `ProviderSettings`
+    /// shared by every type, so it can be deserialised with `deny_unknown_fields`.
+    fn build(&self, instance : &str, config : &serde_yaml::Value) -> Result<Arc<dyn NotificationHandler>, ConfigError>;
+}
+
+/// The provider instances configured in `notification_providers`, built once from a `Config`.
+#[derive(Clone)]
+pub struct ProviderRegistry {
+    factories : HashMap<String, Arc<dyn ProviderFactory>>,
+    providers : HashMap<String, Provider>
+}
+
+impl Default for ProviderRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register(discord::DiscordFactory);
+        registry.register(pushover::PushoverFactory);
+        registry
+    }
+}
+
+impl ProviderRegistry {
+    /// A registry without any of the built-in provider types.
+    pub fn empty() -> Self {
+        Self {
+            factories: HashMap::new(),
+            providers: HashMap::new()
+        }
+    }
+
+    pub fn register<F : ProviderFactory + 'static>(&mut self, factory : F) {
+        self.factories.insert(factory.kind().to_lowercase(), Arc::new(factory));
+    }
+
+    /// Builds every provider instance of the config, replacing the ones built previously.
+    ///
+    /// Instances that fail to build are left out and logged, the first error is returned once
+    /// every instance has been attempted.
+    pub fn load(&mut self, conf : &Config) -> Result<(), ConfigError> {
+        self.providers.clear();
+        let mut first_err = None;
+
+        for instance in conf.notification_providers.keys() {
+            match self.build(conf, instance) {
+                Ok(provider) => {
+                    self.providers.insert(instance.clone(), provider);
+                },
+                Err(err) => {
+                    error!("Unable to load provider {}: {}", instance, err);
+                    first_err.get_or_insert(err);
+                }
+            }
+        }
+
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(())
+        }
+    }
+
+    pub fn build(&self, conf : &Config, instance : &str) -> Result<Provider, ConfigError> {
+        let kind = conf.get_notification_provider_type(instance)
+            .ok_or_else(|| ConfigError::Message(format!("No {} config entry was found", instance)))?;
+        let factory = self.factories.get(&kind)
+            .ok_or_else(|| ConfigError::Message(format!("Unknown provider type {} for {}", kind, instance)))?;
+        let (settings, own) = ProviderSettings::split(&conf.notification_providers[instance]);
+
+        Ok(Provider {
+            name: instance.to_owned(),
+            kind: kind.clone(),
+            description: factory.description().to_owned(),
+            settings: serde_yaml::from_value(settings)
+                .map_err(|err| ConfigError::Message(format!("Invalid settings for {}: {}", instance, err)))?,
+            handler: factory.build(instance, &own)
+                .map_err(|err| ConfigError::Message(format!("Invalid {} config for {}: {}", kind, instance, err)))?
+        })
+    }
+
+    pub fn get(&self, instance : &str) -> Option<&Provider> {
+        self.providers.get(instance)
+    }
+
+    pub fn contains(&self, instance : &str) -> bool {
+        self.providers.contains_key(instance)
+    }
+
+    pub fn providers(&self) -> impl Iterator<Item = &Provider> {
+        self.providers.values()
+    }
+
+    pub fn kinds(&self) -> impl Iterator<Item = &str> {
+        self.factories.keys().map(|k| k.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use serde::Deserialize;
+    use crate::config::{Config, ConfigError};
+    use crate::notifications::{Event, NotificationHandler};
+    use crate::notifications::testing::ScriptedHandler;
+    use super::{ProviderFactory, ProviderRegistry};
+
+    /// Config of the `webhook` type, which has to reject the shared settings to be split off.
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct WebhookConfig {
+        url : String
+    }
+
+    struct WebhookFactory;
+
+    impl ProviderFactory for WebhookFactory {
+        fn kind(&self) -> &str {
+            "webhook"
+        }
+
+        fn description(&self) -> &str {
+            "Posts notifications to a URL"
+        }
+
+        fn build(&self, _instance : &str, config : &serde_yaml::Value) -> Result<Arc<dyn NotificationHandler>, ConfigError> {
+            let conf : WebhookConfig = serde_yaml::from_value(config.clone())
+                .map_err(|err| ConfigError::Message(err.to_string()))?;
+            assert_eq!(conf.url, "https://example.com/hook");
+            Ok(ScriptedHandler::failing(Vec::new()))
+        }
+    }
+
+    #[test]
+    fn test_register() {
+        let conf : Config = serde_yaml::from_str("
+notification_providers:
+  hook:
+    type: Webhook
+    enable: true
+    events: [connect]
+    rate_limit:
+      max: 5
+    url: https://example.com/hook
+  broken:
+    type: webhook
+    url: https://example.com/hook
+    method: PUT
+").unwrap();
+
+        let mut registry = ProviderRegistry::default();
+        registry.register(WebhookFactory);
+        assert!(registry.kinds().any(|kind| kind == "webhook"));
+
+        // The instance with a field its type doesn't know fails, the other one still loads
+        assert!(registry.load(&conf).is_err());
+        assert!(!registry.contains("broken"));
+
+        let hook = registry.get("hook").unwrap();
+        assert_eq!((hook.kind.as_str(), hook.description.as_str()), ("webhook", "Posts notifications to a URL"));
+        assert!(hook.enabled());
+        assert_eq!(hook.settings.events, Some(vec![Event::Connect]));
+        assert_eq!(hook.settings.rate_limit.as_ref().map(|limit| (limit.max, limit.period)), Some((5, 60)));
+    }
+}
@@ -0,0 +1,122 @@
+use chrono::{DateTime, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Serialize, Deserialize};
+use crate::notifications::{Event, NotificationData, Severity};
+use crate::schedule::{deserialize_time, TimeWindow};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QuietAction {
+    /// Don't send the notification at all, the event is still logged
+    Mute,
+    /// Send the notification with a lower severity
+    Downgrade,
+    /// Hold the notification back and send a summary once the window has ended
+    Defer
+}
+
+/// A window during which notifications of a provider instance or route are muted, downgraded
+/// or deferred.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(from = "RawQuietHours")]
+pub struct QuietHours {
+    #[serde(flatten)]
+    pub window : TimeWindow,
+    #[serde(default = "default_action")]
+    pub action : QuietAction,
+    /// Severity notifications are downgraded to
+    #[serde(default = "default_severity")]
+    pub severity : Severity,
+    /// Events the rule applies to, every event if left out
+    #[serde(default)]
+    pub events : Vec<Event>
+}
+
+/// `QuietHours` as written in the config. serde doesn't support `deny_unknown_fields` along
+/// with `flatten`, so the fields of the window are spelled out here.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawQuietHours {
+    #[serde(default)]
+    days : Vec<Weekday>,
+    #[serde(deserialize_with = "deserialize_time")]
+    from : NaiveTime,
+    #[serde(deserialize_with = "deserialize_time")]
+    to : NaiveTime,
+    #[serde(default)]
+    timezone : Option<Tz>,
+    #[serde(default = "default_action")]
+    action : QuietAction,
+    #[serde(default = "default_severity")]
+    severity : Severity,
+    #[serde(default)]
+    events : Vec<Event>
+}
+
+impl From<RawQuietHours> for QuietHours {
+    fn from(raw : RawQuietHours) -> Self {
+        Self {
+            window: TimeWindow { days: raw.days, from: raw.from, to: raw.to, timezone: raw.timezone },
+            action: raw.action,
+            severity: raw.severity,
+            events: raw.events
+        }
+    }
+}
+
+fn default_action() -> QuietAction { QuietAction::Mute }
+
+fn default_severity() -> Severity { Severity::Lowest }
+
+impl QuietHours {
+    pub fn is_active(&self, event : &Event, now : &DateTime<Utc>) -> bool {
+        (self.events.is_empty() || self.events.contains(event)) && self.window.contains(now)
+    }
+}
+
+/// Returns the first rule active for the event.
+pub fn active<'a>(rules : &'a [QuietHours], event : &Event, now : &DateTime<Utc>) -> Option<&'a QuietHours> {
+    rules.iter().find(|rule| rule.is_active(event, now))
+}
+
+/// Notifications deferred for a provider instance, along with the rules that deferred them.
+#[derive(Default)]
+pub struct Deferred {
+    pub rules : Vec<QuietHours>,
+    pub items : Vec<NotificationData>
+}
+
+impl Deferred {
+    /// Whether any of the rules that deferred notifications still applies.
+    pub fn is_active(&self, now : &DateTime<Utc>) -> bool {
+        self.rules.iter().any(|rule| rule.action == QuietAction::Defer && rule.window.contains(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use crate::notifications::{Event, Severity};
+    use super::{QuietAction, QuietHours};
+
+    #[test]
+    fn test_quiet_hours_config() {
+        let rule : QuietHours = serde_yaml::from_str(r#"
+days: [mon, tue, wed, thu, fri]
+from: "18:00"
+to: "08:00"
+timezone: Europe/Copenhagen
+action: downgrade
+events: [disconnect]
+"#).unwrap();
+
+        assert_eq!(rule.action, QuietAction::Downgrade);
+        assert_eq!(rule.severity, Severity::Lowest);
+
+        // 2024-05-06 is a monday, Copenhagen is UTC+2 in may
+        let evening = Utc.with_ymd_and_hms(2024, 5, 6, 17, 0, 0).unwrap();
+        assert!(rule.is_active(&Event::Disconnect, &evening));
+        assert!(!rule.is_active(&Event::Connect, &evening));
+        assert!(!rule.is_active(&Event::Disconnect, &Utc.with_ymd_and_hms(2024, 5, 6, 12, 0, 0).unwrap()));
+    }
+}
@@ -0,0 +1,183 @@
+use chrono::{Local, TimeZone};
+use serde::{Serialize, Deserialize};
+use crate::history::Session;
+use crate::schedule::{Interval, Schedule};
+use crate::sessions::format_duration;
+use crate::status::{format_bytes, PeerStatus};
+
+/// A status report sent on a schedule.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ReportConfig {
+    pub schedule : Schedule,
+    /// Provider instances the report is sent through, routed like other events if left out
+    #[serde(default)]
+    pub providers : Vec<String>,
+    /// Flag peers that haven't connected in this many days
+    #[serde(default)]
+    pub inactive_after : Option<u64>
+}
+
+impl ReportConfig {
+    /// Seconds covered by a report, i.e. the time since the previous one.
+    pub fn period(&self) -> u64 {
+        match self.schedule.interval {
+            Interval::Daily => 86400,
+            Interval::Weekly => 7 * 86400
+        }
+    }
+}
+
+/// A peer as it appears in a report.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PeerReport {
+    pub public_key : String,
+    pub friendly_name : Option<String>,
+    pub connected : bool,
+    /// Unix timestamp of the latest handshake
+    pub last_seen : Option<u64>,
+    /// Seconds connected during the period, if the session history is enabled
+    pub connected_secs : Option<u64>,
+    pub rx_bytes : Option<u64>,
+    pub tx_bytes : Option<u64>,
+    /// Hasn't connected within `inactive_after` days
+    pub inactive : bool
+}
+
+/// Builds a report of the peers over the period from `from` to `to`.
+///
+/// Connected time and traffic come from `sessions`, which should include the ongoing ones, and
+/// are left out without a history. The traffic of a session only partly within the period is
+/// counted in proportion.
+pub fn build(conf : &ReportConfig, peers : &[PeerStatus], sessions : Option<&[Session]>, from : u64, to : u64) -> Vec<PeerReport> {
+    let mut reports : Vec<PeerReport> = peers.iter().map(|peer| {
+        let mut report = PeerReport {
+            public_key: peer.public_key.clone(),
+            friendly_name: peer.friendly_name.clone(),
+            connected: peer.connected,
+            last_seen: peer.latest_handshake,
+            connected_secs: None,
+            rx_bytes: None,
+            tx_bytes: None,
+            inactive: false
+        };
+
+        if let Some(sessions) = sessions {
+            let (mut secs, mut rx, mut tx) = (0, 0, 0);
+            for session in sessions.iter().filter(|session| session.public_key == peer.public_key) {
+                let overlap = session.ended.min(to).saturating_sub(session.started.max(from));
+                let share = |bytes : u64| match session.duration {
+                    0 => bytes,
+                    duration => (bytes as u128 * overlap.min(duration) as u128 / duration as u128) as u64
+                };
+                secs += overlap;
+                rx += share(session.rx_bytes);
+                tx += share(session.tx_bytes);
+            }
+            report.connected_secs = Some(secs);
+            report.rx_bytes = Some(rx);
+            report.tx_bytes = Some(tx);
+        }
+
+        if let Some(days) = conf.inactive_after {
+            let threshold = to.saturating_sub(days.saturating_mul(86400));
+            report.inactive = !peer.connected && peer.latest_handshake.map(|seen| seen < threshold).unwrap_or(true);
+        }
+
+        report
+    }).collect();
+
+    reports.sort_by(|a, b| name(a).cmp(name(b)));
+    reports
+}
+
+fn name(report : &PeerReport) -> &str {
+    report.friendly_name.as_deref().unwrap_or(&report.public_key)
+}
+
+/// Formats a report as the message of a notification.
+pub fn format(conf : &ReportConfig, reports : &[PeerReport], from : u64, to : u64) -> String {
+    let time = |ts : u64| match Local.timestamp_opt(ts as i64, 0) {
+        chrono::LocalResult::Single(val) => val.format("%Y-%m-%d %H:%M").to_string(),
+        _ => "?".to_owned()
+    };
+
+    let mut lines = vec![format!("Status report from {} to {}:", time(from), time(to))];
+    for report in reports {
+        let mut line = format!("{}: {}", name(report), if report.connected { "connected" } else { "disconnected" });
+        if !report.connected {
+            line.push_str(&match report.last_seen {
+                Some(seen) => format!(", last seen {}", time(seen)),
+                None => ", never seen".to_owned()
+            });
+        }
+        if let (Some(secs), Some(rx), Some(tx)) = (report.connected_secs, report.rx_bytes, report.tx_bytes) {
+            line.push_str(&format!(", connected for {}, {} received, {} sent", format_duration(secs), format_bytes(rx as i64), format_bytes(tx as i64)));
+        }
+        if report.inactive {
+            line.push_str(&format!(" (inactive for more than {} days)", conf.inactive_after.unwrap_or(0)));
+        }
+        lines.push(line);
+    }
+
+    if reports.is_empty() {
+        lines.push("No peers".to_owned());
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::history::Session;
+    use crate::status::PeerStatus;
+    use super::{build, ReportConfig};
+
+    fn peer(public_key : &str, connected : bool, latest_handshake : Option<u64>) -> PeerStatus {
+        PeerStatus {
+            public_key: public_key.to_owned(),
+            friendly_name: None,
+            interface: "wg0".to_owned(),
+            connected,
+            latest_handshake,
+            handshake_age: None,
+            endpoint: None,
+            allowed_ips: "10.2.98.3/32".to_owned(),
+            transfer_rx: 0,
+            transfer_tx: 0,
+            persistent_keepalive: 25,
+            stale: false
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let conf : ReportConfig = serde_yaml::from_str("
+schedule:
+  interval: daily
+  at: \"08:00\"
+inactive_after: 30
+").unwrap();
+        let (from, to) = (10_000_000, 10_000_000 + conf.period());
+
+        let session = Session {
+            public_key: "laptop".to_owned(),
+            friendly_name: None,
+            interface: "wg0".to_owned(),
+            endpoints: Vec::new(),
+            started: from - 1000,
+            ended: from + 3000,
+            duration: 4000,
+            rx_bytes: 4000,
+            tx_bytes: 400
+        };
+        let peers = [peer("laptop", false, Some(from + 3000)), peer("old", false, Some(1)), peer("new", false, None)];
+        let reports = build(&conf, &peers, Some(&[session]), from, to);
+
+        let names : Vec<&str> = reports.iter().map(|r| r.public_key.as_str()).collect();
+        assert_eq!(names, vec!["laptop", "new", "old"]);
+        assert_eq!((reports[0].connected_secs, reports[0].rx_bytes, reports[0].tx_bytes), (Some(3000), Some(3000), Some(300)));
+        assert!(!reports[0].inactive);
+        assert!(reports[1].inactive && reports[2].inactive);
+        assert_eq!(reports[1].connected_secs, Some(0));
+    }
+}
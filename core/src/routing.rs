@@ -0,0 +1,186 @@
+use std::net::SocketAddr;
+use ipnet::IpNet;
+use serde::{Serialize, Deserialize};
+use crate::config::Config;
+use crate::notifications::{Event, NotificationData, Severity};
+use crate::quiet_hours::QuietHours;
+
+/// A routing rule deciding which provider instances receive an event.
+///
+/// Every criterion that is set has to match, criteria left empty match anything.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    #[serde(default)]
+    pub events : Vec<Event>,
+    /// Public keys or friendly names
+    #[serde(default)]
+    pub peers : Vec<String>,
+    /// Tags assigned to peers through `peer_tags`
+    #[serde(default)]
+    pub tags : Vec<String>,
+    #[serde(default)]
+    pub interfaces : Vec<String>,
+    /// Subnets the peer endpoint has to be part of
+    #[serde(default)]
+    pub subnets : Vec<IpNet>,
+    /// Provider instances, i.e. keys of `notification_providers`
+    pub providers : Vec<String>,
+    /// Overrides the priority of the providers that support one
+    #[serde(default)]
+    pub priority : Option<i32>,
+    /// Overrides the severity the providers would map the event to
+    #[serde(default)]
+    pub severity : Option<Severity>,
+    #[serde(default)]
+    pub quiet_hours : Vec<QuietHours>,
+    /// Keep evaluating the following routes after this one has matched
+    #[serde(default, rename = "continue")]
+    pub continue_matching : bool
+}
+
+#[derive(Clone, Debug)]
+pub struct RouteTarget {
+    pub provider : String,
+    pub priority : Option<i32>,
+    pub severity : Option<Severity>,
+    pub quiet_hours : Vec<QuietHours>
+}
+
+impl Route {
+    pub fn matches(&self, conf : &Config, data : &NotificationData) -> bool {
+        if !self.events.is_empty() && !self.events.contains(&data.event) {
+            return false;
+        }
+
+        let needs_peer = !self.peers.is_empty() || !self.tags.is_empty() || !self.interfaces.is_empty() || !self.subnets.is_empty();
+        let peer = match &data.peer {
+            Some(peer) => peer,
+            None => return !needs_peer
+        };
+
+        if !self.peers.is_empty() {
+            let friendly_name = conf.friendly_names.get(&peer.public_key);
+            let matched = self.peers.iter().any(|p| *p == peer.public_key || Some(p) == friendly_name);
+            if !matched {
+                return false;
+            }
+        }
+
+        if !self.tags.is_empty() {
+            let matched = match conf.peer_tags.get(&peer.public_key) {
+                Some(tags) => self.tags.iter().any(|t| tags.contains(t)),
+                None => false
+            };
+            if !matched {
+                return false;
+            }
+        }
+
+        if !self.interfaces.is_empty() && !self.interfaces.contains(&peer.interface) {
+            return false;
+        }
+
+        if !self.subnets.is_empty() {
+            let ip = peer.endpoint.as_ref().and_then(|ep| ep.parse::<SocketAddr>().ok()).map(|addr| addr.ip());
+            let matched = match ip {
+                Some(ip) => self.subnets.iter().any(|subnet| subnet.contains(&ip)),
+                None => false
+            };
+            if !matched {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Resolves the provider instances an event should be sent to.
+///
+/// Without any routes configured every provider instance receives every event. Otherwise the
+/// routes are evaluated in order and the first match wins, unless it has `continue` set.
+pub fn resolve(conf : &Config, data : &NotificationData) -> Vec<RouteTarget> {
+    let mut targets : Vec<RouteTarget> = Vec::new();
+
+    if conf.routes.is_empty() {
+        for provider in conf.notification_providers.keys() {
+            targets.push(RouteTarget { provider: provider.clone(), priority: None, severity: None, quiet_hours: Vec::new() });
+        }
+        return targets;
+    }
+
+    for route in &conf.routes {
+        if !route.matches(conf, data) {
+            continue;
+        }
+
+        for provider in &route.providers {
+            if !targets.iter().any(|t| t.provider == *provider) {
+                targets.push(RouteTarget { provider: provider.clone(), priority: route.priority, severity: route.severity, quiet_hours: route.quiet_hours.clone() });
+            }
+        }
+
+        if !route.continue_matching {
+            break;
+        }
+    }
+
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::notifications::{Event, NotificationData, PeerInfo};
+
+    static CONFIG : &str = r#"
+notification_providers:
+  pushover:
+    enable: true
+  discord_log:
+    type: discord
+    enable: true
+peer_tags:
+  c2VydmVy: [servers]
+routes:
+  - events: [disconnect]
+    tags: [servers]
+    providers: [pushover]
+    priority: 2
+  - providers: [discord_log]
+"#;
+
+    fn data(event : Event, public_key : &str) -> NotificationData {
+        NotificationData {
+            msg: "".to_owned(),
+            event,
+            peer: Some(PeerInfo { public_key: public_key.to_owned(), interface: "wg0".to_owned(), endpoint: Some("10.2.2.68:62299".to_owned()) }),
+            priority: None,
+            severity: None
+        }
+    }
+
+    #[test]
+    fn test_first_matching_route_wins() {
+        let conf : Config = serde_yaml::from_str(CONFIG).unwrap();
+
+        let targets = super::resolve(&conf, &data(Event::Disconnect, "c2VydmVy"));
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].provider, "pushover");
+        assert_eq!(targets[0].priority, Some(2));
+
+        let targets = super::resolve(&conf, &data(Event::Connect, "c2VydmVy"));
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].provider, "discord_log");
+    }
+
+    #[test]
+    fn test_no_routes_fans_out() {
+        let mut conf : Config = serde_yaml::from_str(CONFIG).unwrap();
+        conf.routes.clear();
+
+        let targets = super::resolve(&conf, &data(Event::Connect, "c2VydmVy"));
+        assert_eq!(targets.len(), 2);
+    }
+}
@@ -0,0 +1,158 @@
+use chrono::{DateTime, Datelike, Days, Local, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Serialize, Deserialize, Deserializer, Serializer};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Interval {
+    Daily,
+    Weekly
+}
+
+/// A recurring point in time, e.g. every day at 08:00 or every monday at 09:30.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Schedule {
+    pub interval : Interval,
+    /// Time of day, formatted as HH:MM
+    #[serde(serialize_with = "serialize_time", deserialize_with = "deserialize_time")]
+    pub at : NaiveTime,
+    /// Day of the week for weekly schedules, defaults to monday
+    #[serde(default)]
+    pub weekday : Option<Weekday>,
+    /// IANA timezone, e.g. Europe/Copenhagen. The local timezone is used if left out
+    #[serde(default)]
+    pub timezone : Option<Tz>
+}
+
+impl Schedule {
+    /// Returns the first occurrence of the schedule after `now`.
+    pub fn next_after(&self, now : &DateTime<Utc>) -> DateTime<Utc> {
+        match self.timezone {
+            Some(tz) => self.next_after_local(&now.with_timezone(&tz)).with_timezone(&Utc),
+            None => self.next_after_local(&now.with_timezone(&Local)).with_timezone(&Utc)
+        }
+    }
+
+    fn next_after_local<T : TimeZone>(&self, now : &DateTime<T>) -> DateTime<T> {
+        let tz = now.timezone();
+        let mut date = now.date_naive();
+
+        loop {
+            let matches_day = match self.interval {
+                Interval::Daily => true,
+                Interval::Weekly => date.weekday() == self.weekday.unwrap_or(Weekday::Mon)
+            };
+
+            if matches_day {
+                // Times skipped by a DST change don't exist, in which case the day is skipped
+                if let Some(candidate) = tz.from_local_datetime(&date.and_time(self.at)).earliest() {
+                    if candidate > *now {
+                        return candidate;
+                    }
+                }
+            }
+
+            date = date.checked_add_days(Days::new(1)).expect("Date out of range");
+        }
+    }
+}
+
+/// A recurring time range, e.g. 22:00 to 07:00 on weekdays.
+///
+/// A range ending before it starts wraps past midnight, and `days` refers to the day it starts
+/// on. A range starting and ending at the same time covers the whole day.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TimeWindow {
+    /// Days the window starts on, every day if left out
+    #[serde(default)]
+    pub days : Vec<Weekday>,
+    #[serde(serialize_with = "serialize_time", deserialize_with = "deserialize_time")]
+    pub from : NaiveTime,
+    #[serde(serialize_with = "serialize_time", deserialize_with = "deserialize_time")]
+    pub to : NaiveTime,
+    /// IANA timezone, e.g. Europe/Copenhagen. The local timezone is used if left out
+    #[serde(default)]
+    pub timezone : Option<Tz>
+}
+
+impl TimeWindow {
+    pub fn contains(&self, now : &DateTime<Utc>) -> bool {
+        match self.timezone {
+            Some(tz) => self.contains_local(&now.with_timezone(&tz)),
+            None => self.contains_local(&now.with_timezone(&Local))
+        }
+    }
+
+    fn contains_local<T : TimeZone>(&self, now : &DateTime<T>) -> bool {
+        let time = now.time();
+        let today = now.weekday();
+        let yesterday = today.pred();
+        let starts_on = |day : Weekday| self.days.is_empty() || self.days.contains(&day);
+
+        if self.from < self.to {
+            starts_on(today) && time >= self.from && time < self.to
+        } else if self.from > self.to {
+            (starts_on(today) && time >= self.from) || (starts_on(yesterday) && time < self.to)
+        } else {
+            starts_on(today)
+        }
+    }
+}
+
+pub fn serialize_time<S : Serializer>(time : &NaiveTime, serializer : S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.format("%H:%M").to_string())
+}
+
+pub fn deserialize_time<'de, D : Deserializer<'de>>(deserializer : D) -> Result<NaiveTime, D::Error> {
+    let raw = String::deserialize(deserializer)?;
+    parse_time(&raw).map_err(serde::de::Error::custom)
+}
+
+pub fn parse_time(raw : &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(raw.trim(), "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(raw.trim(), "%H:%M:%S"))
+        .map_err(|_| format!("invalid time of day `{}`, expected HH:MM", raw))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc, Weekday};
+    use super::{Interval, Schedule, TimeWindow, parse_time};
+
+    #[test]
+    fn test_next_after() {
+        let daily = Schedule { interval: Interval::Daily, at: parse_time("08:00").unwrap(), weekday: None, timezone: Some(chrono_tz::UTC) };
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 9, 0, 0).unwrap();
+        assert_eq!(daily.next_after(&now), Utc.with_ymd_and_hms(2024, 5, 2, 8, 0, 0).unwrap());
+
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 7, 0, 0).unwrap();
+        assert_eq!(daily.next_after(&now), Utc.with_ymd_and_hms(2024, 5, 1, 8, 0, 0).unwrap());
+
+        // 2024-05-01 is a wednesday
+        let weekly = Schedule { interval: Interval::Weekly, at: parse_time("08:00").unwrap(), weekday: Some(Weekday::Mon), timezone: Some(chrono_tz::UTC) };
+        assert_eq!(weekly.next_after(&now), Utc.with_ymd_and_hms(2024, 5, 6, 8, 0, 0).unwrap());
+
+        // Copenhagen is UTC+2 in may
+        let copenhagen = Schedule { timezone: Some(chrono_tz::Europe::Copenhagen), ..daily };
+        assert_eq!(copenhagen.next_after(&now), Utc.with_ymd_and_hms(2024, 5, 2, 6, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn test_time_window_past_midnight() {
+        let window = TimeWindow {
+            days: vec![Weekday::Fri],
+            from: parse_time("22:00").unwrap(),
+            to: parse_time("07:00").unwrap(),
+            timezone: Some(chrono_tz::Europe::Copenhagen)
+        };
+
+        // 2024-05-03 is a friday, Copenhagen is UTC+2 in may
+        assert!(!window.contains(&Utc.with_ymd_and_hms(2024, 5, 3, 19, 59, 0).unwrap()));
+        assert!(window.contains(&Utc.with_ymd_and_hms(2024, 5, 3, 20, 0, 0).unwrap()));
+        assert!(window.contains(&Utc.with_ymd_and_hms(2024, 5, 4, 4, 59, 0).unwrap()));
+        assert!(!window.contains(&Utc.with_ymd_and_hms(2024, 5, 4, 5, 0, 0).unwrap()));
+        assert!(!window.contains(&Utc.with_ymd_and_hms(2024, 5, 4, 20, 0, 0).unwrap()));
+    }
+}
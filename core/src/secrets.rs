@@ -0,0 +1,224 @@
+use std::fmt;
+use serde::{Serialize, Deserialize};
+use serde_yaml::Value;
+use crate::config::ConfigError;
+
+const REDACTED : &str = "[redacted]";
+
+/// A config value that shouldn't end up in logs, e.g. an API key or webhook URL.
+///
+/// `Debug` and `Display` print a placeholder, `expose` returns the actual value.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new<S : Into<String>>(value : S) -> Self {
+        Self(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+/// Fields that can be read from a file outside of provider instances.
+pub const SECRET_FIELDS : &[&str] = &["webhook_url", "api_key", "device_key"];
+
+/// Resolves the secrets of a config in place.
+///
+/// `${NAME}` in a string is replaced with the environment variable `NAME`, and `$$` with a
+/// single `$`. A key ending in `_file`, e.g. `api_key_file`, is replaced with the key without
+/// the suffix, set to the contents of the file it points to. That's done for any key of a
+/// provider instance in `notification_providers`, and for `SECRET_FIELDS` elsewhere, so names
+/// such as the ones in `friendly_names` are left alone.
+pub fn resolve(value : &mut Value) -> Result<(), ConfigError> {
+    resolve_with(value, &|name| std::env::var(name).ok())
+}
+
+pub fn resolve_with(value : &mut Value, env : &dyn Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+    interpolate_all(value, env)?;
+
+    // Read after interpolating, so the contents of the files are taken as is
+    if let Some(Value::Mapping(providers)) = value.get_mut("notification_providers") {
+        for (_, instance) in providers.iter_mut() {
+            read_files(instance, true)?;
+        }
+    }
+    read_files(value, false)
+}
+
+fn interpolate_all(value : &mut Value, env : &dyn Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+    match value {
+        Value::String(raw) => {
+            *raw = interpolate(raw, env)?;
+        },
+        Value::Sequence(items) => {
+            for item in items {
+                interpolate_all(item, env)?;
+            }
+        },
+        Value::Mapping(mapping) => {
+            for (_, val) in mapping.iter_mut() {
+                interpolate_all(val, env)?;
+            }
+        },
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Replaces `<key>_file` keys with `<key>` set to the contents of the file, for any key if
+/// `any_key` is set or only for `SECRET_FIELDS` otherwise.
+fn read_files(value : &mut Value, any_key : bool) -> Result<(), ConfigError> {
+    match value {
+        Value::Sequence(items) => {
+            for item in items {
+                read_files(item, any_key)?;
+            }
+        },
+        Value::Mapping(mapping) => {
+            for (_, val) in mapping.iter_mut() {
+                read_files(val, any_key)?;
+            }
+
+            let file_keys : Vec<String> = mapping.iter()
+                .filter_map(|(key, _)| key.as_str())
+                .filter(|key| match key.strip_suffix("_file") {
+                    Some(field) => !field.is_empty() && (any_key || SECRET_FIELDS.contains(&field)),
+                    None => false
+                })
+                .map(|key| key.to_owned())
+                .collect();
+
+            for file_key in file_keys {
+                let key = file_key.trim_end_matches("_file").to_owned();
+                let path = mapping.remove(&Value::String(file_key.clone())).unwrap_or(Value::Null);
+                let path = path.as_str()
+                    .ok_or_else(|| ConfigError::Message(format!("{} must be a path", file_key)))?;
+
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|err| ConfigError::Message(format!("Unable to read {} from {}: {}", key, path, err)))?;
+                if mapping.insert(Value::String(key.clone()), Value::String(contents.trim_end_matches(['\r', '\n']).to_owned())).is_some() {
+                    return Err(ConfigError::Message(format!("Both {} and {} are set", key, file_key)));
+                }
+            }
+        },
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn interpolate(raw : &str, env : &dyn Fn(&str) -> Option<String>) -> Result<String, ConfigError> {
+    if !raw.contains('$') {
+        return Ok(raw.to_owned());
+    }
+
+    let mut out = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+
+        if let Some(after) = rest.strip_prefix("$$") {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after.find('}')
+                .ok_or_else(|| ConfigError::Message(format!("Unterminated ${{ in `{}`", raw)))?;
+            let name = &after[..end];
+            let val = env(name)
+                .ok_or_else(|| ConfigError::Message(format!("Environment variable {} is not set", name)))?;
+            out.push_str(&val);
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+
+    Ok(out)
+}
+
+/// Whether a config key is likely to hold a secret.
+pub fn is_secret_key(key : &str) -> bool {
+    let key = key.to_lowercase();
+    ["key", "token", "secret", "password", "url"].iter().any(|word| key.contains(word))
+}
+
+/// Returns a copy of `value` with the values of secret-looking keys replaced by a placeholder.
+pub fn redact(value : &Value) -> Value {
+    match value {
+        Value::Sequence(items) => Value::Sequence(items.iter().map(redact).collect()),
+        Value::Mapping(mapping) => Value::Mapping(mapping.iter().map(|(key, val)| {
+            let val = match key.as_str() {
+                Some(name) if is_secret_key(name) && !val.is_mapping() && !val.is_sequence() => Value::String(REDACTED.to_owned()),
+                _ => redact(val)
+            };
+            (key.clone(), val)
+        }).collect()),
+        _ => value.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{redact, resolve_with};
+
+    #[test]
+    fn test_resolve_secrets() {
+        let path = std::env::temp_dir().join(format!("wg_activity_notify_secret_{}", std::process::id()));
+        std::fs::write(&path, "device-key-from-file\n").unwrap();
+
+        let mut value : serde_yaml::Value = serde_yaml::from_str(&format!(r#"
+pushover:
+  api_key: "${{PUSHOVER_TOKEN}}"
+  device_key_file: {}
+  description: "costs $$5"
+"#, path.display())).unwrap();
+
+        let env = |name : &str| (name == "PUSHOVER_TOKEN").then(|| "token-from-env".to_owned());
+        resolve_with(&mut value, &env).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(value["pushover"]["api_key"].as_str(), Some("token-from-env"));
+        assert_eq!(value["pushover"]["device_key"].as_str(), Some("device-key-from-file"));
+        assert_eq!(value["pushover"]["description"].as_str(), Some("costs $5"));
+        assert!(value["pushover"].get("device_key_file").is_none());
+
+        let redacted = redact(&value);
+        assert_eq!(redacted["pushover"]["api_key"].as_str(), Some("[redacted]"));
+        assert_eq!(redacted["pushover"]["description"].as_str(), Some("costs $5"));
+
+        // Only provider instances and secret fields are read from files
+        let mut value : serde_yaml::Value = serde_yaml::from_str(&format!(r#"
+notification_providers:
+  hook:
+    token_file: {0}
+friendly_names:
+  backup_file: {0}
+"#, path.display())).unwrap();
+        std::fs::write(&path, "token-from-file\n").unwrap();
+        resolve_with(&mut value, &env).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(value["notification_providers"]["hook"]["token"].as_str(), Some("token-from-file"));
+        assert_eq!(value["friendly_names"]["backup_file"].as_str(), Some(path.to_str().unwrap()));
+
+        let mut missing : serde_yaml::Value = serde_yaml::from_str("url: ${MISSING}").unwrap();
+        assert!(resolve_with(&mut missing, &env).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+
+/// Sessions of a single peer since the tracker was last reset.
+#[derive(Debug, Clone, Default)]
+pub struct PeerSessions {
+    pub sessions : u32,
+    /// Seconds spent connected in sessions that have ended
+    pub connected_secs : u64,
+    /// Start of the ongoing session, if the peer is connected
+    pub connected_since : Option<u64>
+}
+
+impl PeerSessions {
+    /// Seconds spent connected up until `now`, including the ongoing session.
+    pub fn total_connected_secs(&self, now : u64, period_start : u64) -> u64 {
+        let ongoing = self.connected_since.map(|since| now.saturating_sub(since.max(period_start))).unwrap_or(0);
+        self.connected_secs + ongoing
+    }
+}
+
+/// Keeps track of connect/disconnect sessions per peer.
+#[derive(Debug, Default)]
+pub struct SessionTracker {
+    peers : HashMap<String, PeerSessions>,
+    period_start : u64
+}
+
+impl SessionTracker {
+    pub fn new(now : u64) -> Self {
+        Self {
+            peers: HashMap::new(),
+            period_start: now
+        }
+    }
+
+    pub fn period_start(&self) -> u64 {
+        self.period_start
+    }
+
+    pub fn connected(&mut self, pub_key : &str, now : u64) {
+        let peer = self.peers.entry(pub_key.to_owned()).or_default();
+        if peer.connected_since.is_none() {
+            peer.sessions += 1;
+            peer.connected_since = Some(now);
+        }
+    }
+
+    pub fn disconnected(&mut self, pub_key : &str, now : u64) {
+        let period_start = self.period_start;
+        if let Some(peer) = self.peers.get_mut(pub_key) {
+            if let Some(since) = peer.connected_since.take() {
+                peer.connected_secs += now.saturating_sub(since.max(period_start));
+            }
+        }
+    }
+
+    pub fn peers(&self) -> &HashMap<String, PeerSessions> {
+        &self.peers
+    }
+
+    /// Starts a new period. Ongoing sessions carry over, counted as one session of the new period.
+    pub fn reset(&mut self, now : u64) {
+        self.peers.retain(|_, peer| peer.connected_since.is_some());
+        for peer in self.peers.values_mut() {
+            peer.sessions = 1;
+            peer.connected_secs = 0;
+        }
+        self.period_start = now;
+    }
+}
+
+/// Formats a number of seconds as e.g. `2h 5m`.
+pub fn format_duration(secs : u64) -> String {
+    let days = secs / 86400;
+    let hours = (secs % 86400) / 3600;
+    let minutes = (secs % 3600) / 60;
+
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m", minutes)
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::{HashMap, VecDeque};
+use serde::{Serialize, Deserialize};
+use crate::Status;
+
+/// How long a status has to hold before it's considered a change.
+///
+/// With both set, whichever is reached first confirms the change. With neither set a change
+/// is confirmed as soon as it's observed.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DebounceConfig {
+    /// Number of consecutive checks
+    #[serde(default)]
+    pub checks : Option<u32>,
+    /// Number of seconds
+    #[serde(default)]
+    pub seconds : Option<u64>
+}
+
+/// Marks a peer as flapping after `transitions` status changes within `window` seconds.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct FlapConfig {
+    /// 0 disables flap detection
+    #[serde(default)]
+    pub transitions : usize,
+    #[serde(default = "default_flap_window")]
+    pub window : u64
+}
+
+impl Default for FlapConfig {
+    fn default() -> Self {
+        Self {
+            transitions: 0,
+            window: default_flap_window()
+        }
+    }
+}
+
+fn default_flap_window() -> u64 { 300 }
+
+struct Candidate {
+    is_disconnected : bool,
+    first_seen : u64,
+    checks : u32
+}
+
+#[derive(Default)]
+pub struct Debouncer {
+    conf : DebounceConfig,
+    candidates : HashMap<String, Candidate>
+}
+
+impl Debouncer {
+    pub fn new(conf : DebounceConfig) -> Self {
+        Self {
+            conf,
+            candidates: HashMap::new()
+        }
+    }
+
+    /// Replaces the config, keeping the changes that are waiting to be confirmed.
+    pub fn set_config(&mut self, conf : DebounceConfig) {
+        self.conf = conf;
+    }
+
+    /// Drops the pending change of a peer, e.g. once it's been removed.
+    pub fn forget(&mut self, pub_key : &str) {
+        self.candidates.remove(pub_key);
+    }
+
+    /// Returns the status a peer should be considered to have, given its confirmed and
+    /// currently observed status.
+    pub fn confirm(&mut self, pub_key : &str, confirmed : &Status, observed : &Status, now : u64) -> Status {
+        if confirmed.is_disconnected == observed.is_disconnected {
+            self.candidates.remove(pub_key);
+            return confirmed.clone();
+        }
+
+        let candidate = self.candidates.entry(pub_key.to_owned()).or_insert(Candidate {
+            is_disconnected: observed.is_disconnected,
+            first_seen: now,
+            checks: 0
+        });
+        if candidate.is_disconnected != observed.is_disconnected {
+            *candidate = Candidate { is_disconnected: observed.is_disconnected, first_seen: now, checks: 0 };
+        }
+        candidate.checks += 1;
+
+        let held_checks = self.conf.checks.map(|checks| candidate.checks >= checks);
+        let held_seconds = self.conf.seconds.map(|seconds| now.saturating_sub(candidate.first_seen) >= seconds);
+        let held = match (held_checks, held_seconds) {
+            (None, None) => true,
+            (checks, seconds) => checks.unwrap_or(false) || seconds.unwrap_or(false)
+        };
+
+        if held {
+            self.candidates.remove(pub_key);
+            return observed.clone();
+        }
+
+        confirmed.clone()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlapState {
+    /// The change should be notified as usual
+    Stable,
+    /// The peer just started flapping, with the number of changes within the window
+    StartedFlapping(usize),
+    /// The peer is flapping and the change should be suppressed
+    Flapping
+}
+
+#[derive(Default)]
+struct PeerFlaps {
+    transitions : VecDeque<u64>,
+    flapping : bool,
+    suppressed : usize
+}
+
+#[derive(Default)]
+pub struct FlapDetector {
+    conf : FlapConfig,
+    peers : HashMap<String, PeerFlaps>
+}
+
+impl FlapDetector {
+    pub fn new(conf : FlapConfig) -> Self {
+        Self {
+            conf,
+            peers: HashMap::new()
+        }
+    }
+
+    /// Replaces the config, keeping the transitions recorded so far.
+    pub fn set_config(&mut self, conf : FlapConfig) {
+        self.conf = conf;
+    }
+
+    /// Drops the changes recorded for a peer, e.g. once it's been removed.
+    pub fn forget(&mut self, pub_key : &str) {
+        self.peers.remove(pub_key);
+    }
+
+    /// Records a confirmed status change of a peer.
+    pub fn record(&mut self, pub_key : &str, now : u64) -> FlapState {
+        if self.conf.transitions == 0 {
+            return FlapState::Stable;
+        }
+
+        let window = self.conf.window;
+        let peer = self.peers.entry(pub_key.to_owned()).or_default();
+        peer.transitions.push_back(now);
+        while peer.transitions.front().is_some_and(|t| now.saturating_sub(*t) > window) {
+            peer.transitions.pop_front();
+        }
+
+        if peer.flapping {
+            peer.suppressed += 1;
+            return FlapState::Flapping;
+        }
+
+        if peer.transitions.len() >= self.conf.transitions {
+            peer.flapping = true;
+            peer.suppressed = 0;
+            return FlapState::StartedFlapping(peer.transitions.len());
+        }
+
+        FlapState::Stable
+    }
+
+    /// Ends the flapping state of a peer that hasn't changed status for a whole window,
+    /// returning the number of changes suppressed while it was flapping.
+    pub fn check_stopped(&mut self, pub_key : &str, now : u64) -> Option<usize> {
+        let peer = self.peers.get_mut(pub_key)?;
+        if !peer.flapping {
+            return None;
+        }
+
+        let last = peer.transitions.back().copied().unwrap_or(0);
+        if now.saturating_sub(last) <= self.conf.window {
+            return None;
+        }
+
+        let suppressed = peer.suppressed;
+        self.peers.remove(pub_key);
+        Some(suppressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Status;
+    use super::{DebounceConfig, Debouncer, FlapConfig, FlapDetector, FlapState};
+
+    fn status(is_disconnected : bool) -> Status {
+        Status { is_disconnected }
+    }
+
+    #[test]
+    fn test_debounce_checks() {
+        let mut debouncer = Debouncer::new(DebounceConfig { checks: Some(3), seconds: None });
+
+        assert!(!debouncer.confirm("peer", &status(false), &status(true), 0).is_disconnected);
+        assert!(!debouncer.confirm("peer", &status(false), &status(true), 5).is_disconnected);
+        assert!(debouncer.confirm("peer", &status(false), &status(true), 10).is_disconnected);
+
+        // Going back resets the count
+        assert!(!debouncer.confirm("peer", &status(false), &status(true), 15).is_disconnected);
+        assert!(!debouncer.confirm("peer", &status(false), &status(false), 20).is_disconnected);
+        assert!(!debouncer.confirm("peer", &status(false), &status(true), 25).is_disconnected);
+    }
+
+    #[test]
+    fn test_flapping() {
+        let mut detector = FlapDetector::new(FlapConfig { transitions: 3, window: 60 });
+
+        assert_eq!(detector.record("peer", 0), FlapState::Stable);
+        assert_eq!(detector.record("peer", 10), FlapState::Stable);
+        assert_eq!(detector.record("peer", 20), FlapState::StartedFlapping(3));
+        assert_eq!(detector.record("peer", 30), FlapState::Flapping);
+        assert_eq!(detector.check_stopped("peer", 60), None);
+        assert_eq!(detector.check_stopped("peer", 91), Some(1));
+        assert_eq!(detector.record("peer", 100), FlapState::Stable);
+    }
+}
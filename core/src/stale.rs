@@ -0,0 +1,59 @@
+use serde::{Serialize, Deserialize};
+use crate::sessions::format_duration;
+use crate::wg::ClientData;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct StaleConfig {
+    /// Days without a handshake after which a peer is stale
+    #[serde(default = "default_after")]
+    pub after : u64
+}
+
+fn default_after() -> u64 { 30 }
+
+impl Default for StaleConfig {
+    fn default() -> Self {
+        Self { after: default_after() }
+    }
+}
+
+/// Whether a peer never had a handshake, or hasn't had one in `after_days` days.
+pub fn is_stale(data : &ClientData, now : u64, after_days : u64) -> bool {
+    data.latest_handshake == 0 || now.saturating_sub(data.latest_handshake) > after_days.saturating_mul(86400)
+}
+
+/// Describes why a peer is stale, e.g. `hasn't connected in 45d 2h 0m`.
+pub fn describe(data : &ClientData, now : u64) -> String {
+    match data.latest_handshake {
+        0 => "has never connected".to_owned(),
+        handshake => format!("hasn't connected in {}", format_duration(now.saturating_sub(handshake)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wg::{parse_dump, ClientData, WgEntry};
+    use super::{describe, is_stale};
+
+    fn client(latest_handshake : u64) -> ClientData {
+        let line = format!("wg0\tQXNodG9uIFNoZXJ5bCBNb3JzZQ==\t(none)\t10.2.2.68:62299\t10.2.98.3/32\t{}\t1204\t1900\t25\n", latest_handshake);
+        match parse_dump(line).remove(0) {
+            WgEntry::Client(data) => data,
+            WgEntry::Server(_) => unreachable!()
+        }
+    }
+
+    #[test]
+    fn test_stale_peers() {
+        let (seen, never) = (client(1643795801), client(0));
+
+        // Peers that never had a handshake are stale right away
+        assert!(!is_stale(&seen, 1643795801 + 86400, 30));
+        assert!(is_stale(&never, 1643795801 + 86400, 30));
+        assert!(is_stale(&seen, 1643795801 + 31 * 86400, 30));
+
+        assert_eq!(describe(&never, 1643795801), "has never connected");
+        assert_eq!(describe(&seen, 1643795801 + 31 * 86400), "hasn't connected in 31d 0h 0m");
+    }
+}
@@ -0,0 +1,109 @@
+use serde::{Serialize, Deserialize};
+use crate::config::Config;
+use crate::stale;
+use crate::wg::{ClientData, WgEntry};
+
+/// State of a peer at a point in time, as shown by the `status` command.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PeerStatus {
+    pub public_key : String,
+    pub friendly_name : Option<String>,
+    pub interface : String,
+    pub connected : bool,
+    /// Unix timestamp of the latest handshake, if there has been one
+    pub latest_handshake : Option<u64>,
+    /// Seconds since the latest handshake
+    pub handshake_age : Option<u64>,
+    pub endpoint : Option<String>,
+    pub allowed_ips : String,
+    pub transfer_rx : i64,
+    pub transfer_tx : i64,
+    /// Seconds, 0 if off
+    pub persistent_keepalive : u64,
+    /// Never had a handshake, or not within `stale_peers.after` days
+    pub stale : bool
+}
+
+/// Whether a peer counts as connected, i.e. it had a handshake within 7 keepalive intervals.
+pub fn is_connected(data : &ClientData, now : u64) -> bool {
+    let handshake_threshold = data.persistent_keepalive.saturating_mul(7);
+    // A handshake in the future, e.g. because of clock skew, counts as just now
+    let seconds_since_last_handshake = now.saturating_sub(data.latest_handshake);
+
+    seconds_since_last_handshake <= handshake_threshold
+}
+
+/// Returns the state of every peer in `entries`, named according to `conf`.
+pub fn peer_statuses(entries : &[WgEntry], conf : &Config, now : u64) -> Vec<PeerStatus> {
+    entries.iter()
+        .filter_map(|entry| match entry {
+            WgEntry::Client(data) => Some(data),
+            WgEntry::Server(_) => None
+        })
+        .map(|data| peer_status(data, conf, now))
+        .collect()
+}
+
+pub fn peer_status(data : &ClientData, conf : &Config, now : u64) -> PeerStatus {
+    let latest_handshake = (data.latest_handshake > 0).then_some(data.latest_handshake);
+    PeerStatus {
+        public_key: data.public_key.clone(),
+        friendly_name: conf.friendly_names.get(&data.public_key).cloned(),
+        interface: data.interface.clone(),
+        connected: is_connected(data, now),
+        latest_handshake,
+        handshake_age: latest_handshake.map(|handshake| now.saturating_sub(handshake)),
+        endpoint: data.endpoint.clone(),
+        allowed_ips: data.allowed_ips.clone(),
+        transfer_rx: data.transfer_rx,
+        transfer_tx: data.transfer_tx,
+        persistent_keepalive: data.persistent_keepalive,
+        stale: stale::is_stale(data, now, conf.stale_peers.clone().unwrap_or_default().after)
+    }
+}
+
+/// Formats a number of bytes with a binary unit, e.g. `1.5 MiB`.
+pub fn format_bytes(bytes : i64) -> String {
+    const UNITS : [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut val = bytes.max(0) as f64;
+    let mut unit = 0;
+    while val >= 1024.0 && unit < UNITS.len() - 1 {
+        val /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes.max(0), UNITS[0])
+    } else {
+        format!("{:.1} {}", val, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::wg::parse_dump;
+    use super::{format_bytes, peer_statuses};
+
+    #[test]
+    fn test_peer_statuses() {
+        let dump = "wg0\tYHeVsK3c5vbTnPQ/B5mfWl4ggpAIGrv4Hi+5SRSmV3w=\tYJ68gGmLBHA5BAljQfMr4yjnjdDEoMxfqhkp1/BtCGU=\t51820\toff\n\
+                    wg0\tZo5RsZ9KTs8gRm3I6Au3pS1Ya7Ta8nV7NPpVXR47DHM=\t(none)\t10.2.2.68:62299\t10.2.98.3/32\t1000\t1572864\t1900\t25\n\
+                    wg0\tdW5kZSBleC4gUXVhcw==\t(none)\t(none)\t10.2.98.6/32\t0\t0\t0\t25\n";
+        let mut conf = Config::default();
+        conf.friendly_names.insert("Zo5RsZ9KTs8gRm3I6Au3pS1Ya7Ta8nV7NPpVXR47DHM=".to_owned(), "laptop".to_owned());
+
+        let statuses = peer_statuses(&parse_dump(dump.to_owned()), &conf, 1100);
+        assert_eq!(statuses.len(), 2);
+        assert!(statuses[0].connected);
+        assert_eq!(statuses[0].friendly_name.as_deref(), Some("laptop"));
+        assert_eq!(statuses[0].handshake_age, Some(100));
+        assert!(!statuses[1].connected);
+        assert_eq!(statuses[1].latest_handshake, None);
+        assert!(!statuses[0].stale && statuses[1].stale);
+
+        assert_eq!(format_bytes(1900), "1.9 KiB");
+        assert_eq!(format_bytes(1572864), "1.5 MiB");
+        assert_eq!(format_bytes(12), "12 B");
+    }
+}
@@ -0,0 +1,18 @@
+use serde::{Serialize, Deserialize};
+use crate::config::Config;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct UnknownPeersConfig {
+    /// Public keys that count as known, in addition to those in `friendly_names` and `peer_tags`
+    #[serde(default)]
+    pub known : Vec<String>
+}
+
+/// Whether a peer is part of the inventory, i.e. it has a friendly name, tags or is listed
+/// in `unknown_peers.known`.
+pub fn is_known(conf : &Config, pub_key : &str) -> bool {
+    conf.friendly_names.contains_key(pub_key)
+        || conf.peer_tags.contains_key(pub_key)
+        || conf.unknown_peers.as_ref().map(|unknown| unknown.known.iter().any(|key| key == pub_key)).unwrap_or(false)
+}
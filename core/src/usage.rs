@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, TimeZone};
+use serde::{Serialize, Deserialize, Deserializer};
+use tracing::error;
+use crate::error::{Error, Result};
+use crate::wg::ClientData;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UsagePeriod {
+    Day,
+    Month
+}
+
+/// Which traffic of a peer counts towards its quota, from the server's point of view.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// Received from the peer
+    Rx,
+    /// Sent to the peer
+    Tx,
+    #[default]
+    Total
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    /// Bytes per period, either a number or a size such as `10 GiB` or `500MB`
+    #[serde(deserialize_with = "deserialize_size")]
+    pub limit : u64,
+    #[serde(default)]
+    pub direction : Direction
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct UsageConfig {
+    /// Period usage is accounted over, in local time
+    #[serde(default = "default_period")]
+    pub period : UsagePeriod,
+    /// Quota of the peers without one of their own, no quota if left out
+    #[serde(default)]
+    pub default_quota : Option<Quota>,
+    /// Quotas by public key or friendly name
+    #[serde(default)]
+    pub quotas : HashMap<String, Quota>,
+    /// Percentage of a quota at which a warning is sent
+    #[serde(default = "default_warning")]
+    pub warning : u8,
+    /// File the usage is kept in across restarts
+    #[serde(default)]
+    pub state : Option<PathBuf>
+}
+
+fn default_period() -> UsagePeriod { UsagePeriod::Month }
+fn default_warning() -> u8 { 80 }
+
+/// Traffic of a peer during the current period.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerUsage {
+    /// e.g. `2024-03` for monthly usage or `2024-03-01` for daily usage
+    pub period : String,
+    pub rx_bytes : u64,
+    pub tx_bytes : u64,
+    last_rx : u64,
+    last_tx : u64,
+    warned : bool,
+    exceeded : bool
+}
+
+impl PeerUsage {
+    pub fn bytes(&self, direction : Direction) -> u64 {
+        match direction {
+            Direction::Rx => self.rx_bytes,
+            Direction::Tx => self.tx_bytes,
+            Direction::Total => self.rx_bytes.saturating_add(self.tx_bytes)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QuotaState {
+    Warning { used : u64, limit : u64 },
+    Exceeded { used : u64, limit : u64 }
+}
+
+/// Accumulates the traffic of every peer per period.
+///
+/// `wg` counters start over when an interface is recreated, so usage is summed from their
+/// changes and a counter going backwards counts as having been reset.
+pub struct UsageTracker {
+    conf : UsageConfig,
+    peers : HashMap<String, PeerUsage>,
+    /// The usage changed since it was last saved
+    dirty : bool
+}
+
+impl UsageTracker {
+    /// Creates a tracker, picking up the usage saved in `conf.state` if there is any.
+    pub fn new(conf : UsageConfig) -> Self {
+        let peers = match &conf.state {
+            Some(path) => load_state(path).unwrap_or_else(|err| {
+                error!("Unable to load usage {}: {}", path.display(), err);
+                HashMap::new()
+            }),
+            None => HashMap::new()
+        };
+
+        Self { conf, peers, dirty: false }
+    }
+
+    pub fn set_config(&mut self, conf : UsageConfig) {
+        // Save to a new file right away
+        self.dirty |= conf.state != self.conf.state;
+        self.conf = conf;
+    }
+
+    pub fn usage(&self, pub_key : &str) -> Option<&PeerUsage> {
+        self.peers.get(pub_key)
+    }
+
+    /// Adds the traffic of a peer since the last poll, returning the quota state it newly reached.
+    pub fn observe<Tz : TimeZone>(&mut self, data : &ClientData, friendly_name : Option<&str>, now : &DateTime<Tz>) -> Option<QuotaState>
+        where Tz::Offset : std::fmt::Display {
+        let period = match self.conf.period {
+            UsagePeriod::Day => now.format("%Y-%m-%d").to_string(),
+            UsagePeriod::Month => now.format("%Y-%m").to_string()
+        };
+        let (rx, tx) = (data.transfer_rx.max(0) as u64, data.transfer_tx.max(0) as u64);
+
+        let previous = self.peers.get(&data.public_key).cloned();
+        let usage = self.peers.entry(data.public_key.clone()).or_insert_with(|| PeerUsage {
+            period: period.clone(),
+            last_rx: rx,
+            last_tx: tx,
+            ..PeerUsage::default()
+        });
+        if usage.period != period {
+            *usage = PeerUsage { period, last_rx: usage.last_rx, last_tx: usage.last_tx, ..PeerUsage::default() };
+        }
+
+        usage.rx_bytes += if rx >= usage.last_rx { rx - usage.last_rx } else { rx };
+        usage.tx_bytes += if tx >= usage.last_tx { tx - usage.last_tx } else { tx };
+        usage.last_rx = rx;
+        usage.last_tx = tx;
+
+        let quota = friendly_name.and_then(|name| self.conf.quotas.get(name))
+            .or_else(|| self.conf.quotas.get(&data.public_key))
+            .or(self.conf.default_quota.as_ref());
+        let state = quota.and_then(|quota| {
+            let used = usage.bytes(quota.direction);
+            if used >= quota.limit && !usage.exceeded {
+                usage.exceeded = true;
+                usage.warned = true;
+                return Some(QuotaState::Exceeded { used, limit: quota.limit });
+            }
+            if used.saturating_mul(100) >= quota.limit.saturating_mul(self.conf.warning as u64) && !usage.warned {
+                usage.warned = true;
+                return Some(QuotaState::Warning { used, limit: quota.limit });
+            }
+            None
+        });
+
+        self.dirty |= previous.as_ref() != Some(usage);
+        state
+    }
+
+    /// Saves the usage to `state`, if set and the usage changed since it was last saved.
+    pub fn persist(&mut self) {
+        if !self.dirty {
+            return;
+        }
+
+        if let Some(path) = &self.conf.state {
+            match save_state(path, &self.peers) {
+                Ok(_) => self.dirty = false,
+                Err(err) => error!("Unable to save usage {}: {}", path.display(), err)
+            }
+        }
+    }
+}
+
+fn load_state(path : &Path) -> Result<HashMap<String, PeerUsage>> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let buf = std::fs::read(path).map_err(|e| Error::Message(e.to_string()))?;
+    serde_json::from_slice(&buf).map_err(|e| Error::Message(e.to_string()))
+}
+
+fn save_state(path : &Path, peers : &HashMap<String, PeerUsage>) -> Result<()> {
+    let buf = serde_json::to_vec(peers).map_err(|e| Error::Message(e.to_string()))?;
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, buf).map_err(|e| Error::Message(e.to_string()))?;
+    std::fs::rename(&tmp_path, path).map_err(|e| Error::Message(e.to_string()))
+}
+
+/// Parses a size such as `1024`, `500MB` or `1.5 GiB` into bytes.
+pub fn parse_size(raw : &str) -> Option<u64> {
+    let raw = raw.trim();
+    let split = raw.find(|c : char| !c.is_ascii_digit() && c != '.').unwrap_or(raw.len());
+    let (number, unit) = raw.split_at(split);
+    let number : f64 = number.parse().ok()?;
+
+    let multiplier : u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "mb" => 1000u64.pow(2),
+        "gb" => 1000u64.pow(3),
+        "tb" => 1000u64.pow(4),
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => return None
+    };
+
+    Some((number * multiplier as f64) as u64)
+}
+
+fn deserialize_size<'de, D : Deserializer<'de>>(deserializer : D) -> std::result::Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Bytes(u64),
+        Size(String)
+    }
+
+    match Raw::deserialize(deserializer)? {
+        Raw::Bytes(bytes) => Ok(bytes),
+        Raw::Size(raw) => parse_size(&raw).ok_or_else(|| serde::de::Error::custom(format!("invalid size `{}`", raw)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use crate::wg::{parse_dump, ClientData, WgEntry};
+    use super::{parse_size, QuotaState, UsageConfig, UsageTracker};
+
+    const KEY : &str = "Zo5RsZ9KTs8gRm3I6Au3pS1Ya7Ta8nV7NPpVXR47DHM=";
+
+    fn client(rx : u64, tx : u64) -> ClientData {
+        let line = format!("wg0\t{}\t(none)\t10.2.2.68:62299\t10.2.98.3/32\t1000\t{}\t{}\t25\n", KEY, rx, tx);
+        match parse_dump(line).remove(0) {
+            WgEntry::Client(data) => data,
+            WgEntry::Server(_) => unreachable!()
+        }
+    }
+
+    #[test]
+    fn test_quota() {
+        let conf : UsageConfig = serde_yaml::from_str("
+period: day
+quotas:
+  laptop:
+    limit: 1 KiB
+").unwrap();
+        let mut tracker = UsageTracker::new(conf);
+        let day = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+
+        assert_eq!(tracker.observe(&client(5000, 5000), Some("laptop"), &day), None);
+        assert_eq!(tracker.observe(&client(5500, 5200), Some("laptop"), &day), None);
+        // The interface was recreated, resetting the counters
+        assert_eq!(tracker.observe(&client(150, 0), Some("laptop"), &day), Some(QuotaState::Warning { used: 850, limit: 1024 }));
+        assert_eq!(tracker.observe(&client(300, 0), Some("laptop"), &day), None);
+        assert_eq!(tracker.observe(&client(400, 0), Some("laptop"), &day), Some(QuotaState::Exceeded { used: 1100, limit: 1024 }));
+        assert_eq!(tracker.observe(&client(500, 0), Some("laptop"), &day), None);
+
+        let next_day = Utc.with_ymd_and_hms(2024, 3, 2, 0, 0, 1).unwrap();
+        assert_eq!(tracker.observe(&client(600, 0), Some("laptop"), &next_day), None);
+        assert_eq!(tracker.usage(KEY).unwrap().rx_bytes, 100);
+
+        // Only saved when something changed
+        let path = std::env::temp_dir().join(format!("wg_activity_notify_usage_{}.json", std::process::id()));
+        tracker.set_config(UsageConfig { state: Some(path.clone()), ..tracker.conf.clone() });
+        tracker.persist();
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+        tracker.observe(&client(600, 0), Some("laptop"), &next_day);
+        tracker.persist();
+        assert!(!path.exists());
+        tracker.observe(&client(700, 0), Some("laptop"), &next_day);
+        tracker.persist();
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(parse_size("1.5 GiB"), Some(1610612736));
+        assert_eq!(parse_size("500MB"), Some(500_000_000));
+        assert_eq!(parse_size("lots"), None);
+    }
+}
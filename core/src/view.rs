@@ -0,0 +1,56 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, RwLock};
+use serde::Serialize;
+use crate::events::DaemonEvent;
+use crate::status::PeerStatus;
+
+/// The current view of a daemon, updated after every poll and shared with the HTTP API.
+#[derive(Debug, Default)]
+pub struct View {
+    pub peers : Vec<PeerStatus>,
+    pub health : Health,
+    events : VecDeque<DaemonEvent>,
+    event_capacity : usize
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Health {
+    /// Unix timestamp of the latest successful poll
+    pub last_poll : Option<u64>,
+    /// Error of the latest poll, if it failed
+    pub last_error : Option<String>,
+    /// Seconds between polls
+    pub update_interval : u64
+}
+
+pub type SharedView = Arc<RwLock<View>>;
+
+impl View {
+    pub fn new(event_capacity : usize) -> Self {
+        Self {
+            event_capacity,
+            ..Default::default()
+        }
+    }
+
+    /// Remembers an event, forgetting the oldest one once `event_capacity` events are kept.
+    pub fn push_event(&mut self, event : DaemonEvent) {
+        if self.event_capacity == 0 {
+            return;
+        }
+        while self.events.len() >= self.event_capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// Events detected at or after the unix timestamp `since`, oldest first.
+    pub fn events_since(&self, since : u64) -> Vec<DaemonEvent> {
+        self.events.iter().filter(|event| event.timestamp >= since).cloned().collect()
+    }
+
+    pub fn peer(&self, key : &str) -> Option<&PeerStatus> {
+        self.peers.iter().find(|peer| peer.public_key == key)
+            .or_else(|| self.peers.iter().find(|peer| peer.friendly_name.as_deref() == Some(key)))
+    }
+}
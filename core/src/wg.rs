@@ -1,6 +1,7 @@
-use std::process::Command;
-use regex::Regex;
+use async_trait::async_trait;
+use tokio::process::Command;
 use thiserror::Error;
+use tracing::debug;
 
 #[derive(Debug, Clone)]
 pub enum WgEntry {
@@ -30,90 +31,109 @@ pub struct ServerData {
     pub fwmark : String,
 }
 
-pub fn get_dump() -> Vec<WgEntry> {
+/// Whether `key` is a WireGuard public key, i.e. 32 bytes encoded as base64.
+pub fn is_valid_public_key(key : &str) -> bool {
+    use base64::Engine;
+    matches!(base64::engine::general_purpose::STANDARD.decode(key), Ok(bytes) if bytes.len() == 32)
+}
+
+/// Where the daemon reads the state of the WireGuard interfaces from.
+#[async_trait]
+pub trait DumpSource : Send + Sync {
+    async fn dump(&self) -> Result<Vec<WgEntry>, WgError>;
+}
+
+/// Reads the state through `wg show all dump`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WgCommand;
+
+#[async_trait]
+impl DumpSource for WgCommand {
+    async fn dump(&self) -> Result<Vec<WgEntry>, WgError> {
+        get_dump().await
+    }
+}
+
+pub async fn get_dump() -> Result<Vec<WgEntry>, WgError> {
     let cmd = Command::new("wg")
         .arg("show")
         .arg("all")
         .arg("dump")
-        .output().unwrap();
+        .output().await
+        .map_err(|e| WgError::Message(format!("unable to run wg: {}", e)))?;
 
-    let output = String::from_utf8(cmd.stdout).unwrap();
+    if !cmd.status.success() {
+        return Err(WgError::Message(format!("wg exited with {}: {}", cmd.status, String::from_utf8_lossy(&cmd.stderr).trim())));
+    }
+
+    let output = String::from_utf8(cmd.stdout)
+        .map_err(|e| WgError::Message(format!("wg output is not valid UTF-8: {}", e)))?;
 
-    parse_dump(output)
+    Ok(parse_dump(output))
 }
 
 pub fn parse_dump(data : String) -> Vec<WgEntry> {
     let mut payload : Vec<WgEntry> = Vec::new();
-    for (_, line) in data.lines().enumerate() {
-        let re = Regex::new(r"\s+").unwrap();
-        let replaced_line = re.replace_all(line, " ").to_string();
-        let splits : Vec<String> = replaced_line.split_whitespace().map(|s| s.to_owned()).collect();
-        match splits.len() {
-            // Server
-            5 => {
-                let entry = WgEntry::Server(ServerData {
-                    interface: splits[0].clone(),
-                    private_key: splits[1].clone(),
-                    public_key: splits[2].clone(),
-                    listen_port: splits[3].clone(),
-                    fwmark: splits[4].clone()
-                });
-                payload.push(entry);
-            },
-            // Client
-            9 => {
-                let preshared_key = {
-                    if splits[2].eq("(none)") {
-                        None
-                    } else {
-                        Some(splits[2].to_owned())
-                    }
-                };
-
-                let endpoint = {
-                    if splits[3].eq("(none)") {
-                        None
-                    } else {
-                        Some(splits[3].to_owned())
-                    }
-                };
-
-                let persistent_keepalive = {
-                  if splits[8].eq("off") {
-                      0
-                  } else {
-                      splits[8].parse::<u64>().unwrap()
-                  }
-                };
-
-                let entry = WgEntry::Client(ClientData {
-                    interface: splits[0].clone(),
-                    public_key: splits[1].clone(),
-                    preshared_key,
-                    endpoint,
-                    allowed_ips: splits[4].clone(),
-                    latest_handshake: splits[5].parse::<u64>().unwrap(),
-                    transfer_rx: splits[6].parse::<i64>().unwrap(),
-                    transfer_tx: splits[7].parse::<i64>().unwrap(),
-                    persistent_keepalive
-                });
-                payload.push(entry);
-            },
-            _ => {}
+    for line in data.lines() {
+        match parse_line(line) {
+            Ok(Some(entry)) => payload.push(entry),
+            Ok(None) => {},
+            Err(err) => debug!("Skipping line of wg dump: {}", err)
         }
     }
 
     payload
 }
 
+/// Parses a single line of `wg show all dump`, `None` for a blank line.
+pub fn parse_line(line : &str) -> Result<Option<WgEntry>, WgError> {
+    let splits : Vec<&str> = line.split_whitespace().collect();
+    match splits.len() {
+        0 => Ok(None),
+        // Server
+        5 => Ok(Some(WgEntry::Server(ServerData {
+            interface: splits[0].to_owned(),
+            private_key: splits[1].to_owned(),
+            public_key: splits[2].to_owned(),
+            listen_port: splits[3].to_owned(),
+            fwmark: splits[4].to_owned()
+        }))),
+        // Client
+        9 => {
+            let optional = |val : &str| if val == "(none)" { None } else { Some(val.to_owned()) };
+            let persistent_keepalive = match splits[8] {
+                "off" => 0,
+                val => parse_field(val, "persistent keepalive")?
+            };
+
+            Ok(Some(WgEntry::Client(ClientData {
+                interface: splits[0].to_owned(),
+                public_key: splits[1].to_owned(),
+                preshared_key: optional(splits[2]),
+                endpoint: optional(splits[3]),
+                allowed_ips: splits[4].to_owned(),
+                latest_handshake: parse_field(splits[5], "latest handshake")?,
+                transfer_rx: parse_field(splits[6], "received bytes")?,
+                transfer_tx: parse_field(splits[7], "sent bytes")?,
+                persistent_keepalive
+            })))
+        },
+        n => Err(WgError::Message(format!("expected 5 fields for an interface or 9 for a peer, found {}", n)))
+    }
+}
+
+fn parse_field<T : std::str::FromStr>(val : &str, name : &str) -> Result<T, WgError> {
+    val.parse::<T>().map_err(|_| WgError::Message(format!("invalid {} `{}`", name, val)))
+}
+
 
 #[derive(Error, Debug)]
 pub enum WgError {
     #[error("context `{0}` could not be found")]
     ContextNotFound(String),
-    #[error("config error: {0:?}")]
-    CustomError(Box<dyn std::error::Error>),
-    #[error("config error: {0}")]
+    #[error("wg error: {0:?}")]
+    CustomError(Box<dyn std::error::Error + Send + Sync>),
+    #[error("wg error: {0}")]
     Message(String)
 }
 
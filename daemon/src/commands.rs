@@ -0,0 +1,237 @@
+use std::path::Path;
+use wg_activity_notify_core::check;
+use wg_activity_notify_core::clock::{Clock, SystemClock};
+use wg_activity_notify_core::config::{Config, ConfigError};
+use wg_activity_notify_core::history::{self, HistoryDb, SessionQuery};
+use wg_activity_notify_core::notifications::{Event, NotificationData, PeerInfo};
+use wg_activity_notify_core::notifications::registry::ProviderRegistry;
+use wg_activity_notify_core::sessions::format_duration;
+use wg_activity_notify_core::status::{format_bytes, peer_statuses};
+use wg_activity_notify_core::wg::{self, WgEntry};
+
+/// Loads the config, exiting if it can't be. A missing file is only accepted if `optional`.
+pub fn load_config(path : &Path, optional : bool) -> Config {
+    match Config::load_from(path) {
+        Ok(conf) => conf,
+        Err(ConfigError::IoError(err)) if optional && err.kind() == std::io::ErrorKind::NotFound => Config::default(),
+        Err(err) => {
+            eprintln!("Unable to load {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn check_config(path : &Path) -> ! {
+    let problems = check::check_file(path, &ProviderRegistry::default());
+    if problems.is_empty() {
+        println!("{} is valid", path.display());
+        std::process::exit(0);
+    }
+
+    for problem in &problems {
+        match problem.line {
+            Some(line) => eprintln!("{}:{}: {}", path.display(), line, problem.msg),
+            None => eprintln!("{}: {}", path.display(), problem.msg)
+        }
+    }
+    std::process::exit(1);
+}
+
+/// Prints a table of the peers of every interface, or only of the stale ones.
+pub async fn status(conf : &Config, only_stale : bool) -> ! {
+    let entries = match wg::get_dump().await {
+        Ok(entries) => entries,
+        Err(err) => {
+            eprintln!("Unable to get the state of the interfaces: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let mut rows = vec![["PEER", "INTERFACE", "STATE", "HANDSHAKE", "ENDPOINT", "RECEIVED", "SENT"].map(String::from)];
+    for peer in peer_statuses(&entries, conf, SystemClock.epoch()) {
+        if only_stale && !peer.stale {
+            continue;
+        }
+        rows.push([
+            peer.friendly_name.unwrap_or(peer.public_key),
+            peer.interface,
+            match (peer.connected, peer.stale) {
+                (true, _) => "connected",
+                (false, true) => "stale",
+                (false, false) => "disconnected"
+            }.to_owned(),
+            match peer.handshake_age {
+                Some(age) if age < 60 => format!("{}s ago", age),
+                Some(age) => format!("{} ago", format_duration(age)),
+                None => "never".to_owned()
+            },
+            peer.endpoint.unwrap_or_else(|| "-".to_owned()),
+            format_bytes(peer.transfer_rx),
+            format_bytes(peer.transfer_tx)
+        ]);
+    }
+
+    print_table(&rows);
+
+    std::process::exit(0);
+}
+
+/// Sends a sample notification through the provider instance `instance`, bypassing routes.
+pub async fn test_notify(conf : &Config, instance : &str, event : Event) -> ! {
+    let mut registry = ProviderRegistry::default();
+    if let Err(err) = registry.load(conf) {
+        eprintln!("Not every notification provider could be loaded: {}", err);
+    }
+
+    let provider = match registry.get(instance) {
+        Some(provider) => provider,
+        None => {
+            let mut instances : Vec<&str> = conf.notification_providers.keys().map(|k| k.as_str()).collect();
+            instances.sort();
+            eprintln!("No provider instance named {} could be loaded, configured instances: {}", instance, instances.join(", "));
+            std::process::exit(1);
+        }
+    };
+
+    let data = NotificationData {
+        msg: format!("This is a test notification from wg_activity_notify, for {:?} events", event),
+        peer: Some(PeerInfo {
+            public_key: "Zo5RsZ9KTs8gRm3I6Au3pS1Ya7Ta8nV7NPpVXR47DHM=".to_owned(),
+            interface: "wg0".to_owned(),
+            endpoint: Some("192.0.2.10:51820".to_owned())
+        }),
+        priority: None,
+        severity: provider.severity(&event).or_else(|| conf.severity.get(&event).copied()).or_else(|| event.default_severity()),
+        event
+    };
+
+    match provider.send(data).await {
+        Ok(()) => {
+            println!("Sent a test notification via {}", instance);
+            std::process::exit(0);
+        },
+        Err(err) => {
+            eprintln!("Unable to send a test notification via {}: {}", instance, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prints what was understood of every line of a `wg show all dump` output.
+pub fn parse(path : &Path) -> ! {
+    let dump = match std::fs::read_to_string(path) {
+        Ok(dump) => dump,
+        Err(err) => {
+            eprintln!("Unable to read {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    };
+
+    let mut failures = 0;
+    for (idx, line) in dump.lines().enumerate() {
+        match wg::parse_line(line) {
+            Ok(None) => {},
+            Ok(Some(WgEntry::Server(data))) => {
+                println!("line {}: interface {}, public key {}, listening on port {}", idx + 1, data.interface, data.public_key, data.listen_port);
+            },
+            Ok(Some(WgEntry::Client(data))) => {
+                println!(
+                    "line {}: peer {} on {}, endpoint {}, allowed ips {}, latest handshake {}, received {}, sent {}, keepalive {}s",
+                    idx + 1,
+                    data.public_key,
+                    data.interface,
+                    data.endpoint.as_deref().unwrap_or("(none)"),
+                    data.allowed_ips,
+                    data.latest_handshake,
+                    data.transfer_rx,
+                    data.transfer_tx,
+                    data.persistent_keepalive
+                );
+            },
+            Err(err) => {
+                failures += 1;
+                println!("line {}: {}", idx + 1, err);
+            }
+        }
+    }
+
+    std::process::exit(if failures > 0 { 1 } else { 0 });
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum HistoryFormat {
+    Table,
+    Csv,
+    Json
+}
+
+/// Prints the recorded sessions matching the peers and time range.
+pub fn history(conf : &Config, peers : &[String], from : Option<u64>, to : Option<u64>, format : HistoryFormat) -> ! {
+    let path = match &conf.history {
+        Some(history) => &history.path,
+        None => {
+            eprintln!("The session history is disabled, set history.path in the config to enable it");
+            std::process::exit(1);
+        }
+    };
+    let db = match HistoryDb::open(path) {
+        Ok(db) => db,
+        Err(err) => {
+            eprintln!("Unable to open {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    };
+
+    // Peers can be given by friendly name too
+    let peers = peers.iter().map(|peer| {
+        conf.friendly_names.iter()
+            .find(|(_, name)| *name == peer)
+            .map(|(key, _)| key.clone())
+            .unwrap_or_else(|| peer.clone())
+    }).collect();
+    let mut sessions = match db.query(&SessionQuery { peers, from, to }) {
+        Ok(sessions) => sessions,
+        Err(err) => {
+            eprintln!("Unable to query {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    };
+    for session in &mut sessions {
+        session.friendly_name = conf.friendly_names.get(&session.public_key).cloned();
+    }
+
+    match format {
+        HistoryFormat::Csv => print!("{}", history::to_csv(&sessions)),
+        HistoryFormat::Json => println!("{}", serde_json::to_string_pretty(&sessions).unwrap()),
+        HistoryFormat::Table => {
+            let mut rows = vec![["PEER", "INTERFACE", "STARTED", "DURATION", "ENDPOINTS", "RECEIVED", "SENT"].map(String::from)];
+            for session in sessions {
+                rows.push([
+                    session.friendly_name.unwrap_or(session.public_key),
+                    session.interface,
+                    chrono::DateTime::from_timestamp(session.started as i64, 0).map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string()).unwrap_or_default(),
+                    format_duration(session.duration),
+                    if session.endpoints.is_empty() { "-".to_owned() } else { session.endpoints.join(", ") },
+                    format_bytes(session.rx_bytes as i64),
+                    format_bytes(session.tx_bytes as i64)
+                ]);
+            }
+            print_table(&rows);
+        }
+    }
+
+    std::process::exit(0);
+}
+
+fn print_table<const N : usize>(rows : &[[String; N]]) {
+    let mut widths = [0; N];
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in rows {
+        let line : Vec<String> = row.iter().zip(widths).map(|(cell, width)| format!("{:width$}", cell, width = width)).collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
@@ -1,21 +1,117 @@
 use std::error::Error;
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
 use tracing::info;
-use tracing_subscriber::Layer;
 use wg_activity_notify_core::Daemon;
+use wg_activity_notify_core::history;
+use wg_activity_notify_core::notifications::Event;
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let conf = match wg_activity_notify_core::config::Config::load() {
-        Ok(val) => val,
-        Err(err) => {
-            panic!("{:?}", err);
-        }
-    };
+mod commands;
+mod reload;
+
+/// Sends notifications when WireGuard peers connect or disconnect.
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    /// Path of the config file, in YAML, TOML or JSON
+    #[arg(short, long, env = "WG_ACTIVITY_NOTIFY_CONFIG", default_value = "config.yml", global = true)]
+    config : PathBuf,
+    #[command(subcommand)]
+    command : Option<Command>
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the daemon, which is the default without a command
+    Run,
+    /// Print the state of every peer
+    Status {
+        /// Only print peers that never connected or haven't in `stale_peers.after` days
+        #[arg(long)]
+        stale : bool
+    },
+    /// Send a sample notification through a provider instance
+    TestNotify {
+        /// Name of the provider instance, i.e. its key in `notification_providers`
+        provider : String,
+        /// Event the sample notification is for
+        #[arg(long, default_value = "connect", value_parser = parse_event)]
+        event : Event
+    },
+    /// Print what was understood of every line of a `wg show all dump` output
+    Parse {
+        dumpfile : PathBuf
+    },
+    /// Validate the config file, exiting with a non-zero status if it has problems
+    CheckConfig,
+    /// Print or export the recorded sessions
+    History {
+        /// Only sessions of this peer, by public key or friendly name, can be repeated
+        #[arg(long)]
+        peer : Vec<String>,
+        /// Only sessions that ended at or after this time, as a unix timestamp, YYYY-MM-DD or RFC 3339
+        #[arg(long, value_parser = parse_time)]
+        from : Option<u64>,
+        /// Only sessions that started at or before this time
+        #[arg(long, value_parser = parse_time)]
+        to : Option<u64>,
+        #[arg(long, value_enum, default_value = "table")]
+        format : commands::HistoryFormat
+    }
+}
+
+fn parse_event(raw : &str) -> Result<Event, String> {
+    serde_yaml::from_str(raw).map_err(|_| format!("unknown event `{}`", raw))
+}
+
+fn parse_time(raw : &str) -> Result<u64, String> {
+    history::parse_time(raw).ok_or_else(|| format!("invalid time `{}`", raw))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+
+    match args.command.unwrap_or(Command::Run) {
+        Command::Run => run(args.config).await,
+        Command::Status { stale } => commands::status(&commands::load_config(&args.config, true), stale).await,
+        Command::TestNotify { provider, event } => commands::test_notify(&commands::load_config(&args.config, false), &provider, event).await,
+        Command::Parse { dumpfile } => commands::parse(&dumpfile),
+        Command::CheckConfig => commands::check_config(&args.config),
+        Command::History { peer, from, to, format } => commands::history(&commands::load_config(&args.config, false), &peer, from, to, format)
+    }
+
+    Ok(())
+}
+
+async fn run(path : PathBuf) {
+    let conf = commands::load_config(&path, false);
     setup_tracing(get_trace_level(&conf.log_level));
     info!("Loading wg_activity_notify");
+    let watch_config = conf.watch_config;
     let mut wg = Daemon::new(conf);
-    wg.run();
+    reload::spawn(path, watch_config, wg.reloader());
+    wg.run_until(shutdown_signal()).await;
+}
 
-    Ok(())
+/// Completes on SIGINT, or SIGTERM as sent by Docker and systemd.
+async fn shutdown_signal() {
+    let ctrl_c = tokio::signal::ctrl_c();
+
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Unable to listen for SIGTERM");
+        tokio::select! {
+            _ = ctrl_c => {},
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = ctrl_c.await;
+    }
 }
 
 fn setup_tracing(log_level : Option<tracing::Level>) {
@@ -38,7 +134,7 @@ fn setup_tracing(log_level : Option<tracing::Level>) {
 }
 
 fn get_trace_level(input : &str) -> Option<tracing::Level> {
-    return match input.to_uppercase().as_str() {
+    match input.to_uppercase().as_str() {
         "TRACE" => Some(tracing::Level::TRACE),
         "DEBUG" => Some(tracing::Level::DEBUG),
         "INFO" => Some(tracing::Level::INFO),
@@ -0,0 +1,85 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use notify::{RecursiveMode, Watcher};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tracing::{debug, error, info};
+use wg_activity_notify_core::config::Config;
+
+/// Reloads the config from `path` on SIGHUP, and on changes to the file if `watch` is set,
+/// handing the configs that could be read to `reloads`.
+pub fn spawn(path : PathBuf, watch : bool, reloads : UnboundedSender<Config>) {
+    let (trigger_tx, mut trigger_rx) = mpsc::unbounded_channel::<&'static str>();
+
+    #[cfg(unix)]
+    {
+        let trigger_tx = trigger_tx.clone();
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .expect("Unable to listen for SIGHUP");
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                if trigger_tx.send("SIGHUP").is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    // The watcher stops once dropped, so it lives as long as the task below
+    let watcher = if watch {
+        match watch_file(&path, trigger_tx) {
+            Ok(watcher) => Some(watcher),
+            Err(err) => {
+                error!("Unable to watch {} for changes: {}", path.display(), err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    tokio::spawn(async move {
+        let _watcher = watcher;
+        while let Some(reason) = trigger_rx.recv().await {
+            // Editors tend to write a file in several steps, wait for them to settle
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            while trigger_rx.try_recv().is_ok() {}
+
+            info!("Reloading config from {} ({})", path.display(), reason);
+            match Config::load_from(&path) {
+                Ok(conf) => {
+                    if reloads.send(conf).is_err() {
+                        break;
+                    }
+                },
+                Err(err) => error!("Unable to reload config, keeping the current one: {}", err)
+            }
+        }
+    });
+}
+
+/// Watches the directory of `path` rather than the file itself, so the file being replaced,
+/// as most editors do, is noticed as well.
+fn watch_file(path : &Path, trigger : UnboundedSender<&'static str>) -> notify::Result<notify::RecommendedWatcher> {
+    let file_name = path.file_name().map(|name| name.to_owned());
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+        _ => PathBuf::from(".")
+    };
+
+    let mut watcher = notify::recommended_watcher(move |res : notify::Result<notify::Event>| {
+        match res {
+            Ok(event) => {
+                let relevant = !event.kind.is_access()
+                    && event.paths.iter().any(|changed| changed.file_name() == file_name.as_deref());
+                if relevant {
+                    debug!("Config file changed: {:?}", event.kind);
+                    let _ = trigger.send("file changed");
+                }
+            },
+            Err(err) => error!("Error watching config file: {}", err)
+        }
+    })?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+    Ok(watcher)
+}